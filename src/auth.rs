@@ -0,0 +1,114 @@
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::Method;
+use actix_web::middleware::{Middleware, Started};
+use actix_web::{HttpRequest, ResponseError, Result};
+use base64;
+
+use config::{AuthConfig, Scope};
+use error::ApiError;
+use request_id::RequestId;
+use server::SharedSettings;
+
+/// Paths that stay reachable without credentials, so that supervisors such as
+/// Kubernetes probes can check on the bridge.
+const PUBLIC_PATHS: &[&str] = &["/healthz", "/readyz"];
+
+/// Middleware guarding the HTTP API with either HTTP Basic credentials or
+/// bearer tokens. `GET` requests require the `read` scope, everything else
+/// requires `control`. Basic credentials always grant `control`.
+pub struct Authenticate {
+    settings: SharedSettings,
+}
+
+impl Authenticate {
+    pub fn new(settings: SharedSettings) -> Self {
+        Authenticate { settings }
+    }
+
+    fn authorize<S>(config: &AuthConfig, req: &HttpRequest<S>) -> Option<Scope> {
+        let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+        let mut parts = value.splitn(2, ' ');
+        let kind = parts.next()?;
+        let credential = parts.next()?.trim();
+
+        if kind.eq_ignore_ascii_case("bearer") {
+            config
+                .tokens
+                .iter()
+                .find(|token| constant_time_eq(token.token.as_bytes(), credential.as_bytes()))
+                .map(|token| token.scope)
+        } else if kind.eq_ignore_ascii_case("basic") {
+            let basic = config.basic.as_ref()?;
+            let decoded = base64::decode(credential).ok()?;
+            let expected = format!("{}:{}", basic.username, basic.password);
+
+            if constant_time_eq(expected.as_bytes(), &decoded[..]) {
+                Some(Scope::Control)
+            } else {
+                None
+            }
+        } else {
+            None
+        }
+    }
+}
+
+impl<S> Middleware<S> for Authenticate {
+    fn start(&self, req: &HttpRequest<S>) -> Result<Started> {
+        let settings = self.settings.read().expect("settings lock");
+        let config = &settings.auth;
+
+        if !config.is_enabled() || PUBLIC_PATHS.contains(&req.path()) {
+            return Ok(Started::Done);
+        }
+
+        let required = if *req.method() == Method::GET {
+            Scope::Read
+        } else {
+            Scope::Control
+        };
+
+        let error = match Self::authorize(config, req) {
+            Some(scope) if scope >= required => return Ok(Started::Done),
+            Some(scope) => ApiError::Forbidden { scope, required },
+            None => ApiError::Unauthorized,
+        };
+        deny(req, &error);
+
+        let mut response = error.error_response();
+        if let ApiError::Unauthorized = error {
+            response.headers_mut().insert(
+                header::WWW_AUTHENTICATE,
+                HeaderValue::from_static("Basic realm=\"castform\""),
+            );
+        }
+
+        Ok(Started::Response(response))
+    }
+}
+
+fn deny<S>(req: &HttpRequest<S>, reason: &ApiError) {
+    let info = req.connection_info();
+    let request_id = req
+        .extensions()
+        .get::<RequestId>()
+        .map(|id| id.to_string())
+        .unwrap_or_default();
+
+    warn!(
+        "[{}] denied {} {} from {}: {}",
+        request_id,
+        req.method(),
+        req.path(),
+        info.remote().unwrap_or("unknown"),
+        reason
+    );
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
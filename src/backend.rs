@@ -0,0 +1,195 @@
+use actix::{Actor, Addr, Context, Handler, MailboxError, Message, Recipient};
+use failure::{err_msg, Error};
+use futures::Future;
+
+use config::{AccountConfig, Config};
+use ecobee::EcobeeActor;
+use fixtures::{FixtureMode, Fixtures};
+use query::{HealthQuery, RawQuery, RulesQuery, SchedulesQuery, StatusQuery, ThermostatsQuery};
+use request_id::Traced;
+use response::ThermostatStatus;
+use simulator::SimulatedThermostat;
+use temperature::Temperature;
+use Result;
+
+/// Source of thermostat state selected with `backend` in the config.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    #[default]
+    Ecobee,
+    Simulator,
+}
+
+/// The messages every backend actor handles: queries for state and devices,
+/// changes, and taking part in reloads and shutdown. Callers don't hold a
+/// `Backend` though, they hold a `BackendAddr`.
+pub trait Backend:
+    Actor<Context = Context<Self>>
+    + Handler<StatusQuery>
+    + Handler<RawQuery>
+    + Handler<ThermostatsQuery>
+    + Handler<HealthQuery>
+    + Handler<RulesQuery>
+    + Handler<SchedulesQuery>
+    + Handler<Traced<ChangeThermostat>>
+    + Handler<Traced<TargetedChange>>
+    + Handler<Traced<RefreshNow>>
+    + Handler<Reconfigure>
+    + Handler<Drain>
+{
+    /// Wraps the address of a started backend of this kind.
+    fn backend_addr(addr: Addr<Self>) -> BackendAddr;
+}
+
+/// Most minutes per hour ecobee runs the fan for through `fanMinOnTime`.
+pub const MAX_FAN_MIN_ON_TIME: u8 = 55;
+
+/// How long a hold lasts.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum HoldType {
+    /// Until it is cancelled, e.g. by resuming the program.
+    #[default]
+    Indefinite,
+    /// Until the program's next change of comfort setting.
+    NextTransition,
+}
+
+impl HoldType {
+    /// The `holdType` parameter of ecobee's `setHold` function.
+    pub fn ecobee_name(self) -> &'static str {
+        match self {
+            HoldType::Indefinite => "indefinite",
+            HoldType::NextTransition => "nextTransition",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum ChangeThermostat {
+    HvacMode(u8),
+    Temperature(Temperature, HoldType),
+    /// Holds the comfort setting with this climate ref, e.g. `away`.
+    Climate(String, HoldType),
+    /// Minutes per hour the fan runs at least, regardless of heating and
+    /// cooling.
+    FanMinOnTime(u8),
+    /// Cancels holds and returns to the thermostat's program.
+    ResumeProgram,
+}
+
+impl Message for ChangeThermostat {
+    type Result = Result<Box<Future<Item = ThermostatStatus, Error = Error> + Send + 'static>>;
+}
+
+/// A change for the thermostat with the given identifier or name, or for the
+/// account's first one, as rules pick it.
+pub struct TargetedChange {
+    pub thermostat: Option<String>,
+    pub change: ChangeThermostat,
+}
+
+impl Message for TargetedChange {
+    type Result = <ChangeThermostat as Message>::Result;
+}
+
+/// Fetches the thermostats' state right away. The returned future resolves
+/// once the backend's cache has been updated.
+pub struct RefreshNow;
+
+impl Message for RefreshNow {
+    type Result = Result<Box<Future<Item = (), Error = Error> + Send + 'static>>;
+}
+
+/// Sends coalesced temperature changes right away and resolves once every
+/// write in flight has completed. Sent on shutdown.
+pub struct Drain;
+
+impl Message for Drain {
+    type Result = Result<Box<Future<Item = (), Error = Error> + Send + 'static>>;
+}
+
+/// Applies the settings from a reloaded config that can change while the
+/// backend is running. Credentials are only read at startup.
+#[derive(Message)]
+pub struct Reconfigure(pub Config);
+
+/// Address of a running backend, one variant per kind of backend. The
+/// variants are matched on every send, so a new kind of backend needs a
+/// variant here and its actor added to the `Handler` bounds of `send` and
+/// `do_send`, next to implementing `Backend`.
+#[derive(Clone)]
+pub enum BackendAddr {
+    Ecobee(Addr<EcobeeActor>),
+    Simulator(Addr<SimulatedThermostat>),
+}
+
+impl BackendAddr {
+    pub fn send<M>(&self, msg: M) -> Box<Future<Item = M::Result, Error = MailboxError>>
+    where
+        M: Message + Send + 'static,
+        M::Result: Send,
+        EcobeeActor: Handler<M>,
+        SimulatedThermostat: Handler<M>,
+    {
+        match *self {
+            BackendAddr::Ecobee(ref addr) => Box::new(addr.send(msg)),
+            BackendAddr::Simulator(ref addr) => Box::new(addr.send(msg)),
+        }
+    }
+
+    pub fn do_send<M>(&self, msg: M)
+    where
+        M: Message + Send + 'static,
+        M::Result: Send,
+        EcobeeActor: Handler<M>,
+        SimulatedThermostat: Handler<M>,
+    {
+        match *self {
+            BackendAddr::Ecobee(ref addr) => addr.do_send(msg),
+            BackendAddr::Simulator(ref addr) => addr.do_send(msg),
+        }
+    }
+
+    /// The backend as a recipient of `M` only, for actors that send it one
+    /// kind of message and are tested against a stub.
+    pub fn recipient<M>(&self) -> Recipient<M>
+    where
+        M: Message + Send + 'static,
+        M::Result: Send,
+        EcobeeActor: Handler<M>,
+        SimulatedThermostat: Handler<M>,
+    {
+        match *self {
+            BackendAddr::Ecobee(ref addr) => addr.clone().recipient(),
+            BackendAddr::Simulator(ref addr) => addr.clone().recipient(),
+        }
+    }
+}
+
+/// Starts the backend selected in `config` for `account`, recording or
+/// replaying its ecobee traffic if `fixtures` is set.
+pub fn start(
+    config: &Config,
+    account: &AccountConfig,
+    fixtures: Option<&FixtureMode>,
+) -> Result<BackendAddr> {
+    match config.backend {
+        BackendKind::Ecobee => {
+            let fixtures = match fixtures {
+                Some(mode) => Some(Fixtures::open(mode, &account.name)?),
+                None => None,
+            };
+            Ok(spawn(EcobeeActor::from_config(config, account, fixtures)?))
+        }
+        BackendKind::Simulator if fixtures.is_some() => {
+            Err(err_msg("recording and replaying need the ecobee backend"))
+        }
+        BackendKind::Simulator => Ok(spawn(SimulatedThermostat::new(config, account))),
+    }
+}
+
+fn spawn<B: Backend>(backend: B) -> BackendAddr {
+    B::backend_addr(B::create(move |_| backend))
+}
@@ -0,0 +1,534 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use actix::{Arbiter, System};
+use clap::{AppSettings, Arg, ArgMatches, SubCommand};
+use failure::{err_msg, Error};
+use futures::future::{self, Either, Loop};
+use futures::{Future, Stream};
+use http::{Method, Request};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client};
+use hyper_tls::HttpsConnector;
+use serde::de::DeserializeOwned;
+use serde_json::{self, Value};
+use serde_urlencoded;
+use tokio::timer::Delay;
+
+use backend::{self, BackendAddr, ChangeThermostat, HoldType, RefreshNow};
+use config::Config;
+use ecobee::{hvac_mode_index, hvac_mode_name};
+use error::ApiError;
+use query::{HealthQuery, StatusQuery, ThermostatsQuery};
+use request_id::{RequestId, Traced};
+use response::{EcobeeStatus, ThermostatSummary};
+use temperature::{Temperature, Units};
+use Result;
+
+/// Seconds to wait for ecobee to authenticate before giving up.
+const READY_TIMEOUT: u64 = 30;
+
+/// A command run against ecobee, either directly or through a running bridge.
+pub enum Command {
+    Status { thermostat: Option<String> },
+    SetTemperature(f32),
+    SetMode(u8),
+    Resume,
+    Thermostats,
+}
+
+enum Output {
+    Status(EcobeeStatus),
+    Thermostats(Vec<ThermostatSummary>),
+}
+
+pub fn subcommands<'a, 'b>() -> Vec<clap::App<'a, 'b>> {
+    vec![
+        SubCommand::with_name("status")
+            .about("print the thermostat status")
+            .arg(
+                Arg::with_name("thermostat")
+                    .long("thermostat")
+                    .value_name("THERMOSTAT")
+                    .help("identifier or name of the thermostat [default: the first one]"),
+            )
+            .args(&client_args()),
+        SubCommand::with_name("set")
+            .about("change the thermostat")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(
+                SubCommand::with_name("temp")
+                    .about("hold a target temperature")
+                    .arg(
+                        Arg::with_name("temperature")
+                            .value_name("TEMPERATURE")
+                            .required(true),
+                    )
+                    .args(&client_args()),
+            )
+            .subcommand(
+                SubCommand::with_name("mode")
+                    .about("set the heating/cooling mode")
+                    .arg(
+                        Arg::with_name("mode")
+                            .value_name("MODE")
+                            .possible_values(&["off", "heat", "cool", "auto"])
+                            .required(true),
+                    )
+                    .args(&client_args()),
+            ),
+        SubCommand::with_name("resume")
+            .about("cancel holds and resume the thermostat's program")
+            .args(&client_args()),
+        SubCommand::with_name("thermostats")
+            .about("list the account's thermostats")
+            .args(&client_args()),
+    ]
+}
+
+fn client_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("remote")
+            .long("remote")
+            .value_name("URL")
+            .help("talk to a running bridge, e.g. http://localhost:8351, instead of ecobee"),
+        Arg::with_name("token")
+            .long("token")
+            .value_name("TOKEN")
+            .env("CASTFORM_TOKEN")
+            .requires("remote")
+            .help("bearer token for the remote bridge"),
+        Arg::with_name("account")
+            .long("account")
+            .value_name("ACCOUNT")
+            .help("ecobee account to use [default: the first one]"),
+        Arg::with_name("units")
+            .long("units")
+            .value_name("UNITS")
+            .possible_values(&["c", "f"])
+            .help("temperature scale [default: the config's, or c with --remote]"),
+        Arg::with_name("json")
+            .long("json")
+            .help("print the response as JSON"),
+    ]
+}
+
+impl Command {
+    /// The client command selected on the command line, if any, and the
+    /// matches holding its options.
+    pub fn from_matches<'a, 'b>(
+        matches: &'a ArgMatches<'b>,
+    ) -> Result<Option<(Command, &'a ArgMatches<'b>)>> {
+        let command = match matches.subcommand() {
+            ("status", Some(args)) => (
+                Command::Status {
+                    thermostat: args.value_of("thermostat").map(str::to_owned),
+                },
+                args,
+            ),
+            ("set", Some(set)) => match set.subcommand() {
+                ("temp", Some(args)) => {
+                    let value = args.value_of("temperature").unwrap_or_default();
+                    let temperature = value
+                        .parse::<f32>()
+                        .ok()
+                        .filter(|temperature| temperature.is_finite())
+                        .ok_or_else(|| format_err!("invalid temperature `{}`", value))?;
+
+                    (Command::SetTemperature(temperature), args)
+                }
+                ("mode", Some(args)) => (
+                    Command::SetMode(hvac_mode_index(args.value_of("mode").unwrap_or_default())),
+                    args,
+                ),
+                _ => return Ok(None),
+            },
+            ("resume", Some(args)) => (Command::Resume, args),
+            ("thermostats", Some(args)) => (Command::Thermostats, args),
+            _ => return Ok(None),
+        };
+
+        Ok(Some(command))
+    }
+
+    /// Runs the command to completion and prints its result. Without
+    /// `--remote` the config at `config_path` is used to talk to ecobee.
+    pub fn run(self, args: &ArgMatches, config_path: &str) -> Result<()> {
+        let system = System::new("castform");
+        let account = args.value_of("account");
+        let units = args.value_of("units").map(parse_units);
+
+        let (units, future) = match args.value_of("remote") {
+            Some(remote) => {
+                let units = units.unwrap_or_default();
+                let remote = Remote::new(remote, args.value_of("token"), account)?;
+                (units, self.remote(remote, units))
+            }
+            None => {
+                let config = Config::load(config_path)?;
+                config.validate()?;
+                let config = direct_config(config);
+
+                let accounts = config.accounts();
+                let account = match account {
+                    Some(name) => accounts
+                        .iter()
+                        .find(|account| account.name == name)
+                        .ok_or_else(|| ApiError::UnknownAccount(name.to_owned()))?,
+                    None => &accounts[0],
+                };
+                let backend = backend::start(&config, account, None)?;
+                let units = units.unwrap_or(config.units);
+                (units, self.direct(backend, units))
+            }
+        };
+
+        let result = Rc::new(RefCell::new(None));
+        let output = result.clone();
+        Arbiter::spawn(future.then(move |res| {
+            *output.borrow_mut() = Some(res);
+            System::current().stop();
+            Ok(())
+        }));
+        let _ = system.run();
+
+        let output = result
+            .borrow_mut()
+            .take()
+            .ok_or_else(|| err_msg("the command did not finish"))??;
+        print(&output, units, args.is_present("json"))
+    }
+
+    fn direct(
+        self,
+        backend: BackendAddr,
+        units: Units,
+    ) -> Box<Future<Item = Output, Error = Error>> {
+        let change = move |backend: BackendAddr, change: ChangeThermostat| {
+            backend
+                .send(Traced::new(RequestId::generate(), change))
+                .map_err(|_| err_msg("mailbox error"))
+                .and_then(|resp| resp)
+                .and_then(|fut| fut)
+                .map(move |status| Output::Status(status.in_units(units)))
+        };
+
+        Box::new(wait_ready(backend.clone()).and_then(
+            move |_| -> Box<Future<Item = Output, Error = Error>> {
+                match self {
+                    Command::Status { thermostat } => Box::new(
+                        backend
+                            .send(StatusQuery(thermostat))
+                            .map_err(|_| err_msg("mailbox error"))
+                            .and_then(|resp| resp)
+                            .map(move |status| Output::Status(status.in_units(units))),
+                    ),
+                    Command::Thermostats => Box::new(
+                        backend
+                            .send(ThermostatsQuery)
+                            .map_err(|_| err_msg("mailbox error"))
+                            .and_then(|resp| resp)
+                            .map(Output::Thermostats),
+                    ),
+                    Command::SetTemperature(value) => Box::new(change(
+                        backend,
+                        ChangeThermostat::Temperature(
+                            Temperature::from_units(value, units),
+                            HoldType::Indefinite,
+                        ),
+                    )),
+                    Command::SetMode(mode) => {
+                        Box::new(change(backend, ChangeThermostat::HvacMode(mode)))
+                    }
+                    Command::Resume => Box::new(change(backend, ChangeThermostat::ResumeProgram)),
+                }
+            },
+        ))
+    }
+
+    fn remote(self, remote: Remote, units: Units) -> Box<Future<Item = Output, Error = Error>> {
+        let mut query = vec![("units", units_param(units).to_owned())];
+
+        match self {
+            Command::Status { thermostat } => {
+                if let Some(thermostat) = thermostat {
+                    query.push(("thermostat", thermostat));
+                }
+                Box::new(
+                    remote
+                        .request(Method::GET, "/status", &query, None)
+                        .map(Output::Status),
+                )
+            }
+            Command::Thermostats => Box::new(
+                remote
+                    .request(Method::GET, "/thermostats", &[], None)
+                    .map(Output::Thermostats),
+            ),
+            Command::SetTemperature(value) => Box::new(
+                remote
+                    .request(
+                        Method::POST,
+                        "/targetTemperature",
+                        &query,
+                        Some(json!({ "temperature": value })),
+                    )
+                    .map(Output::Status),
+            ),
+            Command::SetMode(mode) => Box::new(
+                remote
+                    .request(
+                        Method::POST,
+                        "/targetHeatingCoolingState",
+                        &query,
+                        Some(json!({ "state": mode })),
+                    )
+                    .map(Output::Status),
+            ),
+            Command::Resume => Box::new(
+                remote
+                    .request(Method::POST, "/resume", &query, None)
+                    .map(Output::Status),
+            ),
+        }
+    }
+}
+
+/// Resolves once the backend holds an ecobee token and has fetched the
+/// thermostats, failing with the first error it records.
+fn wait_ready(backend: BackendAddr) -> impl Future<Item = (), Error = Error> {
+    let deadline = Instant::now() + Duration::from_secs(READY_TIMEOUT);
+    let refresh = backend.clone();
+
+    future::loop_fn(backend, move |backend| {
+        backend
+            .send(HealthQuery)
+            .map_err(|_| err_msg("mailbox error"))
+            .and_then(move |health| {
+                if health.authenticated {
+                    return Either::A(future::ok(Loop::Break(())));
+                }
+
+                match health.last_error {
+                    Some(error) => Either::A(future::err(err_msg(error.message))),
+                    None if Instant::now() >= deadline => Either::A(future::err(err_msg(
+                        "timed out waiting for ecobee to authenticate",
+                    ))),
+                    None => Either::B(
+                        Delay::new(Instant::now() + Duration::from_millis(100))
+                            .map(move |_| Loop::Continue(backend))
+                            .from_err(),
+                    ),
+                }
+            })
+    })
+    .and_then(move |_| {
+        refresh
+            .send(Traced::new(RequestId::generate(), RefreshNow))
+            .map_err(|_| err_msg("mailbox error"))
+            .and_then(|resp| resp)
+            .and_then(|fut| fut)
+    })
+}
+
+/// HTTP client for a running bridge.
+struct Remote {
+    client: Client<HttpsConnector<HttpConnector>>,
+    base: String,
+    token: Option<String>,
+}
+
+impl Remote {
+    fn new(url: &str, token: Option<&str>, account: Option<&str>) -> Result<Remote> {
+        let mut base = url.to_owned();
+        while base.ends_with('/') {
+            base.pop();
+        }
+        if let Some(account) = account {
+            base.push_str(&format!("/accounts/{}", account));
+        }
+
+        Ok(Remote {
+            client: Client::builder().build(HttpsConnector::new(1)?),
+            base,
+            token: token.map(str::to_owned),
+        })
+    }
+
+    /// Sends a request and parses the JSON response. Error responses are
+    /// reported with the bridge's message.
+    fn request<T: DeserializeOwned + 'static>(
+        &self,
+        method: Method,
+        path: &str,
+        query: &[(&str, String)],
+        body: Option<Value>,
+    ) -> Box<Future<Item = T, Error = Error>> {
+        let mut uri = format!("{}{}", self.base, path);
+        if !query.is_empty() {
+            match serde_urlencoded::to_string(query) {
+                Ok(query) => uri.push_str(&format!("?{}", query)),
+                Err(e) => return Box::new(future::err(e.into())),
+            }
+        }
+
+        let mut builder = Request::builder();
+        builder.method(method).uri(uri.as_str());
+        if let Some(ref token) = self.token {
+            builder.header("Authorization", format!("Bearer {}", token).as_str());
+        }
+        let request = match body {
+            Some(body) => builder
+                .header("Content-Type", "application/json")
+                .body(Body::from(body.to_string())),
+            None => builder.body(Body::empty()),
+        };
+        let request = match request {
+            Ok(request) => request,
+            Err(e) => return Box::new(future::err(e.into())),
+        };
+
+        Box::new(
+            self.client
+                .request(request)
+                .and_then(|response| {
+                    let status = response.status();
+                    response
+                        .into_body()
+                        .concat2()
+                        .map(move |body| (status, body))
+                })
+                .map_err(move |e| format_err!("{}: {}", uri, e))
+                .and_then(|(status, body)| {
+                    if status.is_success() {
+                        serde_json::from_slice(&body).map_err(Error::from)
+                    } else {
+                        let message = serde_json::from_slice::<Value>(&body)
+                            .ok()
+                            .and_then(|error| error["message"].as_str().map(str::to_owned))
+                            .unwrap_or_else(|| String::from_utf8_lossy(&body).into_owned());
+
+                        Err(format_err!("{}: {}", status, message))
+                    }
+                }),
+        )
+    }
+}
+
+/// `config` as a one-off command uses it. Rules and schedules belong to the
+/// running bridge and must not be triggered, and so does `state_dir`: ecobee
+/// refresh tokens work once, so refreshing the bridge's stored token would
+/// lock the bridge out.
+fn direct_config(mut config: Config) -> Config {
+    config.rules.clear();
+    config.schedules.clear();
+    config.state_dir = None;
+    config
+}
+
+fn parse_units(units: &str) -> Units {
+    match units {
+        "f" => Units::Fahrenheit,
+        _ => Units::Celsius,
+    }
+}
+
+fn units_param(units: Units) -> &'static str {
+    match units {
+        Units::Celsius => "c",
+        Units::Fahrenheit => "f",
+    }
+}
+
+fn print(output: &Output, units: Units, json: bool) -> Result<()> {
+    if json {
+        let json = match *output {
+            Output::Status(ref status) => serde_json::to_string_pretty(status)?,
+            Output::Thermostats(ref thermostats) => serde_json::to_string_pretty(thermostats)?,
+        };
+        println!("{}", json);
+
+        return Ok(());
+    }
+
+    let symbol = match units {
+        Units::Celsius => "°C",
+        Units::Fahrenheit => "°F",
+    };
+
+    match *output {
+        Output::Status(ref status) => {
+            println!(
+                "mode:         {}",
+                hvac_mode_name(status.target_heating_cooling_state).unwrap_or("unknown")
+            );
+            println!(
+                "temperature:  {:.1}{} (target {:.1}{})",
+                status.current_temperature, symbol, status.target_temperature, symbol
+            );
+            println!(
+                "humidity:     {:.0}% (target {:.0}%)",
+                status.current_relative_humidity,
+                status.target_relative_humidity * 100.0
+            );
+            println!("updated:      {}s ago", status.age);
+        }
+        Output::Thermostats(ref thermostats) => {
+            for thermostat in thermostats {
+                println!(
+                    "{}  {} ({})",
+                    thermostat.identifier, thermostat.name, thermostat.hvac_mode
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+
+    use fixtures::FixtureMode;
+
+    #[test]
+    fn direct_mode_leaves_the_bridge_token_alone() {
+        let dir = env::temp_dir().join(format!("castform-cli-token-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let token = dir.join("default-token.json");
+        let saved = r#"{"access_token":"bridge","refresh_token":"bridge"}"#;
+        fs::write(&token, saved).unwrap();
+
+        let config: Config = ::toml::from_str(&format!(
+            "client_id = \"id\"\nusername = \"me\"\npassword = \"secret\"\nstate_dir = {:?}",
+            dir.display().to_string()
+        ))
+        .expect("config");
+        let config = direct_config(config);
+        let account = config.accounts().remove(0);
+        let fixtures = PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures"));
+        let ready = Arc::new(Mutex::new(None));
+        let result = ready.clone();
+
+        System::run(move || {
+            let backend = backend::start(&config, &account, Some(&FixtureMode::Replay(fixtures)))
+                .expect("backend");
+            Arbiter::spawn(wait_ready(backend).then(move |res| {
+                *result.lock().expect("result") = Some(res.map_err(|e| e.to_string()));
+                System::current().stop();
+                Ok(())
+            }));
+        });
+
+        assert_eq!(*ready.lock().expect("result"), Some(Ok(())));
+        assert_eq!(fs::read_to_string(&token).unwrap(), saved);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,6 +1,425 @@
-#[derive(Deserialize)]
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+
+use log::LevelFilter;
+use toml::value::{Table, Value};
+
+use backend::BackendKind;
+use logging::{LogFormat, REDACTED};
+use presence::{self, PresenceConfig};
+use ratelimit::RateLimitConfig;
+use retry::RetryConfig;
+use rules::{self, RuleConfig};
+use schedules::{self, ScheduleConfig};
+use temperature::Units;
+use Result;
+
+/// Prefix of environment variables that override config file values.
+const ENV_PREFIX: &str = "CASTFORM_";
+
+/// Name of the account configured by the top level credentials.
+pub const DEFAULT_ACCOUNT: &str = "default";
+
+#[derive(Deserialize, Serialize, Clone)]
 pub struct Config {
+    #[serde(default)]
     pub client_id: String,
+    /// Read `client_id` from this file instead, e.g. a mounted secret.
+    pub client_id_file: Option<String>,
+    #[serde(default)]
     pub username: String,
+    #[serde(default)]
     pub password: String,
+    /// Read `password` from this file instead, e.g. a mounted secret.
+    pub password_file: Option<String>,
+    /// Separate ecobee accounts, replacing the single account above.
+    #[serde(default)]
+    pub accounts: Vec<AccountConfig>,
+    /// Where thermostat state comes from, `ecobee` or `simulator`.
+    #[serde(default)]
+    pub backend: BackendKind,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    pub tls_client_ca: Option<String>,
+    #[serde(default)]
+    pub units: Units,
+    /// Seconds between polls of the thermostat state.
+    #[serde(default = "default_poll_interval")]
+    pub poll_interval: u64,
+    /// Seconds to wait for in-flight requests and ecobee writes on shutdown.
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+    /// Seconds since the last successful poll after which `/readyz` fails.
+    #[serde(default = "default_ready_max_age")]
+    pub ready_max_age: u64,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    /// One of `error`, `warn`, `info`, `debug` or `trace`.
+    pub log_level: Option<String>,
+    #[serde(default)]
+    pub log_format: LogFormat,
+    /// Local automations evaluated on every new thermostat state.
+    #[serde(default)]
+    pub rules: Vec<RuleConfig>,
+    /// Changes run at local times, independently of the ecobee program.
+    #[serde(default)]
+    pub schedules: Vec<ScheduleConfig>,
+    /// Away holds driven by who reported being home.
+    #[serde(default)]
+    pub presence: PresenceConfig,
+    /// Directory for state kept across restarts, like the ecobee tokens and
+    /// when schedules last ran.
+    pub state_dir: Option<String>,
+}
+
+fn default_poll_interval() -> u64 {
+    30
+}
+
+fn default_shutdown_timeout() -> u64 {
+    30
+}
+
+fn default_ready_max_age() -> u64 {
+    120
+}
+
+impl Config {
+    /// Reads the config file at `path`, applies `CASTFORM_*` environment
+    /// overrides and resolves `*_file` secrets.
+    pub fn load(path: &str) -> Result<Config> {
+        let mut contents = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut contents))
+            .map_err(|e| format_err!("{}: {}", path, e))?;
+
+        let mut table: Table =
+            ::toml::from_str(&contents).map_err(|e| format_err!("{}: {}", path, e))?;
+        for (name, value) in env::vars() {
+            if name.starts_with(ENV_PREFIX) {
+                apply_override(&mut table, &name, value)?;
+            }
+        }
+
+        let mut config: Config = Value::Table(table).try_into()?;
+        resolve_secret("client_id", &mut config.client_id, &config.client_id_file)?;
+        resolve_secret("password", &mut config.password, &config.password_file)?;
+        for account in &mut config.accounts {
+            resolve_secret("client_id", &mut account.client_id, &account.client_id_file)?;
+            resolve_secret("password", &mut account.password, &account.password_file)?;
+        }
+
+        Ok(config)
+    }
+
+    /// Checks the merged config for values that would only fail later, once
+    /// the bridge is already running, and reports all of them at once.
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = Vec::new();
+
+        // Simulated thermostats need no ecobee credentials.
+        let ecobee = self.backend == BackendKind::Ecobee;
+
+        if self.accounts.is_empty() {
+            if ecobee {
+                check_credentials(&mut problems, "", ENV_PREFIX, &self.default_account());
+            }
+        } else {
+            if !self.client_id.is_empty() || !self.username.is_empty() || !self.password.is_empty()
+            {
+                problems.push(
+                    "client_id, username and password cannot be combined with [[accounts]]"
+                        .to_owned(),
+                );
+            }
+
+            let mut names = Vec::new();
+            for account in &self.accounts {
+                if !is_valid_name(&account.name) {
+                    problems.push(format!(
+                        "account name `{}` must be non-empty and only use letters, digits, `-` and `_`",
+                        account.name
+                    ));
+                } else if names.contains(&&account.name) {
+                    problems.push(format!("account name `{}` is used twice", account.name));
+                }
+                names.push(&account.name);
+
+                if ecobee {
+                    check_credentials(
+                        &mut problems,
+                        &format!("accounts.{}.", account.name),
+                        "",
+                        account,
+                    );
+                }
+            }
+        }
+
+        if let Some(ref basic) = self.auth.basic {
+            if basic.username.is_empty() || basic.password.is_empty() {
+                problems.push("auth.basic needs a username and a password".to_owned());
+            }
+        }
+        if self.auth.tokens.iter().any(|token| token.token.is_empty()) {
+            problems.push("auth.tokens entries must not have an empty token".to_owned());
+        }
+
+        if let Some(ref level) = self.log_level {
+            if level.parse::<LevelFilter>().is_err() {
+                problems.push(format!(
+                    "log_level `{}` is not one of off, error, warn, info, debug or trace",
+                    level
+                ));
+            }
+        }
+
+        if self.poll_interval == 0 {
+            problems.push("poll_interval must be at least 1 second".to_owned());
+        }
+        if self.retry.max_attempts == 0 {
+            problems.push("retry.max_attempts must be at least 1".to_owned());
+        }
+        if self.retry.request_timeout == 0 {
+            problems.push("retry.request_timeout must be at least 1 second".to_owned());
+        }
+        if self.rate_limit.requests_per_second < 0.0 {
+            problems.push("rate_limit.requests_per_second must not be negative".to_owned());
+        }
+        if self.rate_limit.requests_per_second > 0.0 && self.rate_limit.burst < 1.0 {
+            problems.push("rate_limit.burst must be at least 1".to_owned());
+        }
+        rules::validate(&self.rules, &mut problems);
+        schedules::validate(&self.schedules, &mut problems);
+        presence::validate(&self.presence, &mut problems);
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(format_err!(
+                "invalid configuration:\n  - {}",
+                problems.join("\n  - ")
+            ))
+        }
+    }
+
+    /// A copy with every credential replaced, safe to print or log.
+    pub fn redacted(&self) -> Config {
+        let mut config = self.clone();
+
+        redact(&mut config.client_id);
+        redact(&mut config.password);
+        for account in &mut config.accounts {
+            redact(&mut account.client_id);
+            redact(&mut account.password);
+        }
+        if let Some(ref mut basic) = config.auth.basic {
+            redact(&mut basic.password);
+        }
+        for token in &mut config.auth.tokens {
+            redact(&mut token.token);
+        }
+
+        config
+    }
+
+    /// The ecobee accounts to run, in order. Without `[[accounts]]` this is
+    /// a single account named `default` using the top level credentials.
+    pub fn accounts(&self) -> Vec<AccountConfig> {
+        if self.accounts.is_empty() {
+            vec![self.default_account()]
+        } else {
+            self.accounts.clone()
+        }
+    }
+
+    fn default_account(&self) -> AccountConfig {
+        AccountConfig {
+            name: DEFAULT_ACCOUNT.to_owned(),
+            client_id: self.client_id.clone(),
+            client_id_file: self.client_id_file.clone(),
+            username: self.username.clone(),
+            password: self.password.clone(),
+            password_file: self.password_file.clone(),
+        }
+    }
+
+    pub fn to_toml(&self) -> Result<String> {
+        // Going through `Value` orders plain keys before tables, which
+        // serializing the struct directly does not.
+        Ok(::toml::to_string(&Value::try_from(self)?)?)
+    }
+}
+
+/// Reports empty credentials, naming the keys with `prefix` and, when
+/// `env_prefix` is set, the environment variables that could provide them.
+fn check_credentials(
+    problems: &mut Vec<String>,
+    prefix: &str,
+    env_prefix: &str,
+    account: &AccountConfig,
+) {
+    for &(name, value, file) in &[
+        ("client_id", &account.client_id, true),
+        ("username", &account.username, false),
+        ("password", &account.password, true),
+    ] {
+        if value.is_empty() {
+            let mut sources = Vec::new();
+            if !env_prefix.is_empty() {
+                sources.push(format!("{}{}", env_prefix, name.to_uppercase()));
+            }
+            if file {
+                sources.push(format!("{}{}_file", prefix, name));
+            }
+
+            let mut problem = format!("{}{} is empty, set it in the config file", prefix, name);
+            if !sources.is_empty() {
+                problem.push_str(&format!(" or with {}", sources.join(" or ")));
+            }
+            problems.push(problem);
+        }
+    }
+}
+
+/// Names of accounts and rules show up in paths and logs, so they are kept
+/// to letters, digits, `-` and `_`.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn redact(secret: &mut String) {
+    if !secret.is_empty() {
+        *secret = REDACTED.to_owned();
+    }
+}
+
+/// Sets the key named by an environment variable, `CASTFORM_LOG_LEVEL` for
+/// `log_level` or `CASTFORM_RETRY__MAX_ATTEMPTS` for `max_attempts` in the
+/// `[retry]` table. Values replacing a string stay strings, anything else is
+/// parsed as a TOML value first so numbers, booleans and arrays work too.
+fn apply_override(table: &mut Table, name: &str, value: String) -> Result<()> {
+    let path = name[ENV_PREFIX.len()..].to_lowercase();
+    let mut keys = path.split("__").collect::<Vec<_>>();
+    let last = keys.pop().unwrap_or_default();
+
+    let mut table = table;
+    for key in keys {
+        let entry = table
+            .entry(key.to_owned())
+            .or_insert_with(|| Value::Table(Table::new()));
+        table = match *entry {
+            Value::Table(ref mut nested) => nested,
+            _ => return Err(format_err!("{}: `{}` is not a table", name, key)),
+        };
+    }
+
+    let value = match table.get(last) {
+        Some(&Value::String(_)) => Value::String(value),
+        _ => parse_value(&value).unwrap_or(Value::String(value)),
+    };
+    table.insert(last.to_owned(), value);
+
+    Ok(())
+}
+
+fn parse_value(value: &str) -> Option<Value> {
+    ::toml::from_str::<Table>(&format!("value = {}", value))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+}
+
+/// Replaces `value` with the contents of `file`, if set.
+fn resolve_secret(name: &str, value: &mut String, file: &Option<String>) -> Result<()> {
+    if let Some(ref path) = *file {
+        *value = read_secret(name, value, path)?;
+    }
+
+    Ok(())
+}
+
+fn read_secret(name: &str, inline: &str, path: &str) -> Result<String> {
+    if !inline.is_empty() {
+        return Err(format_err!(
+            "set either {} or {}_file, not both",
+            name,
+            name
+        ));
+    }
+
+    let mut secret = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut secret))
+        .map_err(|e| format_err!("{}_file {}: {}", name, path, e))?;
+
+    while secret.ends_with('\n') || secret.ends_with('\r') {
+        secret.pop();
+    }
+
+    Ok(secret)
+}
+
+/// Credentials for one ecobee account. `name` identifies the account in HTTP
+/// routes, logs and health reports.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct AccountConfig {
+    pub name: String,
+    #[serde(default)]
+    pub client_id: String,
+    pub client_id_file: Option<String>,
+    #[serde(default)]
+    pub username: String,
+    #[serde(default)]
+    pub password: String,
+    pub password_file: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct AuthConfig {
+    pub basic: Option<BasicCredentials>,
+    #[serde(default)]
+    pub tokens: Vec<ApiToken>,
+}
+
+impl AuthConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.basic.is_some() || !self.tokens.is_empty()
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct BasicCredentials {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct ApiToken {
+    pub token: String,
+    pub scope: Scope,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Read,
+    Control,
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Scope::Read => write!(f, "read"),
+            Scope::Control => write!(f, "control"),
+        }
+    }
 }
@@ -1,22 +1,47 @@
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
-use std::time::Duration;
-
-use actix::{Actor, Arbiter, AsyncContext, Context, Handler, Message};
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use actix::{
+    Actor, Addr, Arbiter, AsyncContext, Context, Handler, Message, MessageResult, SpawnHandle,
+};
 use failure::{err_msg, Error};
+use futures::future::{self, Either, Loop, Shared};
+use futures::sync::oneshot;
 use futures::{Future, IntoFuture, Stream};
 use http::request::Builder;
-use http::Request;
+use http::{Request, StatusCode};
 use hyper::client::HttpConnector;
 use hyper::{Body, Client, Uri};
 use hyper_tls::HttpsConnector;
 use serde::de::DeserializeOwned;
 use serde_json;
-use serde_json::Value;
 use serde_urlencoded;
-
-use config::Config;
-use query::EcobeeQuery;
-use response::{EcobeeResponse, EcobeeStatus};
+use tokio::timer::{Deadline, Delay};
+
+use backend::{
+    Backend, BackendAddr, ChangeThermostat, Drain, HoldType, Reconfigure, RefreshNow,
+    TargetedChange, MAX_FAN_MIN_ON_TIME,
+};
+use config::{AccountConfig, Config};
+use error::ApiError;
+use fixtures::Fixtures;
+use logging::REDACTED;
+use query::{HealthQuery, RawQuery, RulesQuery, SchedulesQuery, StatusQuery, ThermostatsQuery};
+use ratelimit::TokenBucket;
+use reconcile::{Conflict, Expected, PendingChange};
+use request_id::{RequestId, Traced};
+use response::{HealthStatus, LastError, ThermostatStatus, ThermostatSummary};
+use retry::{CircuitBreaker, RetryConfig};
+use rules::{self, FiringOutcome, RuleEngine, Trigger};
+use schedules::{self, Scheduler};
+use state::{load_json_state, save_json_state, unix_time};
+use temperature::Temperature;
+use thermostat::Thermostat;
 use Result;
 
 trait FutureExt<I, E, F: Future<Item = I, Error = E>> {
@@ -32,52 +57,84 @@ where
     }
 }
 
-fn ftoc(f: f32) -> f32 {
-    ((f - 32.0) / 1.8 * 10.0).round() / 10.0
+fn age(time: SystemTime) -> u64 {
+    time.elapsed()
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
 }
 
-fn ctof(c: f32) -> f32 {
-    (c * 1.8) + 32.0
+/// Maps a HomeKit heating/cooling state to ecobee's `hvacMode`.
+pub fn hvac_mode_name(mode: u8) -> Option<&'static str> {
+    match mode {
+        0 => Some("off"),
+        1 => Some("heat"),
+        2 => Some("cool"),
+        3 => Some("auto"),
+        _ => None,
+    }
 }
 
-#[derive(Deserialize, Clone, Debug)]
+pub fn hvac_mode_index(mode: &str) -> u8 {
+    match mode {
+        "auto" => 3,
+        "cool" => 2,
+        "heat" => 1,
+        _ => 0,
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
 struct AuthToken {
     access_token: String,
     refresh_token: String,
 }
 
+impl fmt::Debug for AuthToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AuthToken")
+            .field("access_token", &REDACTED)
+            .field("refresh_token", &REDACTED)
+            .finish()
+    }
+}
+
 #[derive(Deserialize, Debug)]
 struct ErrorMessage {
     error: String,
     error_description: String,
 }
 
+/// The `{"status": {"code", "message"}}` envelope of ecobee API responses.
 #[derive(Deserialize, Debug)]
-#[serde(rename_all = "camelCase")]
-struct ThermostatRuntime {
-    #[serde(rename = "actualTemperature")]
-    temperature: usize,
-    #[serde(rename = "actualHumidity")]
-    humidity: usize,
-    desired_heat: usize,
-    desired_cool: usize,
-    desired_humidity: usize,
+struct StatusEnvelope {
+    status: ApiStatus,
 }
 
 #[derive(Deserialize, Debug)]
-#[serde(rename_all = "camelCase")]
-struct ThermostatSettings {
-    hvac_mode: String,
+struct ApiStatus {
+    code: i64,
+    message: String,
 }
 
-#[derive(Deserialize, Debug)]
-struct Thermostat {
-    identifier: String,
-    runtime: ThermostatRuntime,
-    settings: ThermostatSettings,
-    #[serde(flatten)]
-    other: HashMap<String, Value>,
-}
+/// ecobee status code for a temporary server side failure.
+const PROCESSING_ERROR: i64 = 3;
+
+/// Milliseconds to wait after a write before refreshing, since ecobee takes a
+/// moment to reflect accepted changes in the thermostat it returns.
+const WRITE_SETTLE_MS: u64 = 2000;
+
+/// Seconds polls may keep disagreeing with an accepted write before ecobee's
+/// state wins and the write is reported as a conflict.
+const RECONCILE_TIMEOUT_SECS: u64 = 120;
+
+/// A fetch of the thermostats that several callers can wait on.
+type SharedRefresh = Shared<Box<Future<Item = (), Error = Error> + Send>>;
+
+/// Errors worth retrying: failed connections, 5xx responses and ecobee's
+/// processing error status.
+#[derive(Debug, Fail)]
+#[fail(display = "{}", _0)]
+struct Transient(Error);
 
 #[derive(Deserialize, Debug)]
 struct ThermostatResponse {
@@ -85,25 +142,50 @@ struct ThermostatResponse {
     thermostats: Vec<Thermostat>,
 }
 
+/// ecobee's answer to thermostat updates, which carries nothing but the
+/// status.
 #[derive(Deserialize, Debug)]
 struct UpdateResponse {
-    #[serde(flatten)]
-    other: HashMap<String, Value>,
-}
-
-#[derive(Debug, Fail)]
-enum ErrorKind {
-    #[fail(display = "remote error: {:?}", _0)]
-    RemoteError(ErrorMessage),
+    status: ApiStatus,
 }
 
 pub struct EcobeeActor {
+    /// Name of the ecobee account, used in logs.
+    account: String,
     client_id: String,
     client: Client<HttpsConnector<HttpConnector>, Body>,
     username: String,
     password: String,
     auth_token: Option<AuthToken>,
+    token_updated: Option<Instant>,
+    /// Where the token is kept across restarts, if `state_dir` is set.
+    token_path: Option<PathBuf>,
     thermostats: Vec<Thermostat>,
+    last_poll: Option<SystemTime>,
+    last_error: Option<(SystemTime, String)>,
+    retry: RetryConfig,
+    breaker: CircuitBreaker,
+    limiter: TokenBucket,
+    coalesce_window: Duration,
+    pending_holds: HashMap<String, PendingHold>,
+    /// Writes sent to ecobee that have not completed yet.
+    in_flight: Arc<AtomicUsize>,
+    poll_interval: Duration,
+    poll_handle: Option<SpawnHandle>,
+    /// ID of the HTTP request whose message is being handled, if any.
+    request_id: Option<RequestId>,
+    /// The fetch of the thermostats in progress, if any.
+    refreshing: Option<SharedRefresh>,
+    /// Accepted writes already applied to `thermostats` that polls have not
+    /// confirmed yet, by thermostat identifier.
+    pending_changes: HashMap<String, PendingChange>,
+    /// The latest unconfirmed write of each thermostat, if any.
+    conflicts: HashMap<String, Conflict>,
+    rules: RuleEngine,
+    schedules: Scheduler,
+    /// Captured traffic recorded to or replayed from, see `--record` and
+    /// `--replay`.
+    fixtures: Option<Arc<Fixtures>>,
 }
 
 impl EcobeeActor {
@@ -126,39 +208,278 @@ impl EcobeeActor {
         url.parse().map_err(From::from)
     }
 
-    pub fn from_config(config: &Config) -> Result<Self> {
+    pub fn from_config(
+        config: &Config,
+        account: &AccountConfig,
+        fixtures: Option<Fixtures>,
+    ) -> Result<Self> {
         Ok(Self {
-            client_id: config.client_id.clone(),
+            account: account.name.clone(),
+            client_id: account.client_id.clone(),
             client: Self::build_client()?,
-            username: config.username.clone(),
-            password: config.password.clone(),
+            username: account.username.clone(),
+            password: account.password.clone(),
             auth_token: None,
+            token_updated: None,
+            token_path: config
+                .state_dir
+                .as_ref()
+                .map(|dir| PathBuf::from(dir).join(format!("{}-token.json", account.name))),
             thermostats: Vec::new(),
+            last_poll: None,
+            last_error: None,
+            retry: config.retry.clone(),
+            breaker: CircuitBreaker::new(&config.retry),
+            limiter: TokenBucket::new(&config.rate_limit),
+            coalesce_window: Duration::from_millis(config.rate_limit.coalesce_window_ms),
+            pending_holds: HashMap::new(),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            poll_interval: Duration::from_secs(config.poll_interval),
+            poll_handle: None,
+            request_id: None,
+            refreshing: None,
+            pending_changes: HashMap::new(),
+            conflicts: HashMap::new(),
+            rules: RuleEngine::new(config),
+            schedules: Scheduler::new(config, &account.name),
+            fixtures: fixtures.map(Arc::new),
         })
     }
 
+    /// (Re)starts polling ecobee every `poll_interval`.
+    fn schedule_poll(&mut self, ctx: &mut Context<Self>) {
+        if let Some(handle) = self.poll_handle.take() {
+            ctx.cancel_future(handle);
+        }
+
+        self.poll_handle = Some(ctx.run_interval(self.poll_interval, |actor, context| {
+            // The fetch runs on its own, nothing waits for scheduled polls.
+            let _ = actor.refresh(context);
+        }));
+    }
+
+    /// Fetches the thermostats and updates the cache. Callers arriving while
+    /// a fetch is already running share it instead of starting another one.
+    fn refresh(&mut self, ctx: &mut Context<Self>) -> SharedRefresh {
+        if let Some(ref refresh) = self.refreshing {
+            debug!("joining the refresh of {} in progress", self.account);
+            return refresh.clone();
+        }
+
+        let addr = ctx.address();
+        let error_addr = ctx.address();
+        let done_addr = ctx.address();
+        let account = self.account.clone();
+        let fetch: Box<Future<Item = (), Error = Error> + Send> = self
+            .get_thermostat()
+            .and_then(move |thermostat| {
+                addr.send(UpdateThermostat(thermostat))
+                    .map_err(|_| err_msg("mailbox error"))
+            })
+            .map_err(move |e| {
+                warn!(
+                    "error occurred when fetching thermostats of {}: {}",
+                    account, e
+                );
+                error_addr.do_send(RecordError(e.to_string()));
+                e
+            })
+            .then(move |result| {
+                done_addr.do_send(RefreshDone);
+                result
+            })
+            .boxify();
+        let refresh = fetch.shared();
+
+        // Run to completion even if every caller stops waiting for it.
+        Arbiter::spawn(refresh.clone().then(|_| Ok(())));
+        self.refreshing = Some(refresh.clone());
+        refresh
+    }
+
+    /// Sends `request`, retrying transient failures with jittered exponential
+    /// backoff. Only requests that are safe to repeat are retried, nothing is
+    /// sent while the circuit breaker is open, and every attempt waits for a
+    /// token from the shared rate limiter.
     fn send_request<R: DeserializeOwned + Send + 'static>(
         &self,
-        request: Request<Body>,
+        request: Request<Vec<u8>>,
+        idempotent: bool,
     ) -> Box<Future<Item = R, Error = Error> + Send> {
-        self.client
-            .request(request)
-            .and_then(|resp| resp.into_body().concat2())
-            .map(|chunk| chunk.to_vec())
-            .map_err(|e| -> Error { e.into() })
-            .and_then(|data| {
-                serde_json::from_slice(&data[..]).map_err(move |e| {
-                    let error_message = serde_json::from_slice::<ErrorMessage>(&data[..]);
-
-                    match error_message {
-                        Ok(message) => ErrorKind::RemoteError(message).into(),
-                        Err(_) => e.into(),
+        let client = self.client.clone();
+        let breaker = self.breaker.clone();
+        let retry = self.retry.clone();
+        let limiter = self.limiter.clone();
+        let fixtures = self.fixtures.clone();
+        let request_id = self.request_id.clone().unwrap_or_else(RequestId::generate);
+
+        future::loop_fn(1, move |attempt| {
+            if !breaker.allow() {
+                return Either::A(future::err(ApiError::CircuitOpen.into()));
+            }
+
+            let breaker = breaker.clone();
+            let retry = retry.clone();
+            let client = client.clone();
+            let fixtures = fixtures.clone();
+            let request_id = request_id.clone();
+            let send_id = request_id.clone();
+            let attempt_request = Self::copy_request(&request);
+            let timeout = Duration::from_secs(retry.request_timeout);
+            let send = Delay::new(Instant::now() + limiter.reserve())
+                .map_err(Error::from)
+                .and_then(move |_| {
+                    Self::send_once(&client, fixtures, attempt_request, send_id, timeout)
+                });
+
+            Either::B(send.then(
+                move |result| -> Box<Future<Item = Loop<R, u32>, Error = Error> + Send> {
+                    let error = match result {
+                        Ok(response) => {
+                            breaker.record_success();
+                            return future::ok(Loop::Break(response)).boxify();
+                        }
+                        Err(error) => error,
+                    };
+
+                    match error.downcast::<Transient>() {
+                        Ok(Transient(error)) => {
+                            breaker.record_failure();
+
+                            if !idempotent || attempt >= retry.max_attempts {
+                                return future::err(error).boxify();
+                            }
+
+                            let delay = retry.backoff(attempt);
+                            warn!(
+                                "[{}] attempt {} failed, retrying in {:?}: {}",
+                                request_id, attempt, delay, error
+                            );
+
+                            Delay::new(Instant::now() + delay)
+                                .map(move |_| Loop::Continue(attempt + 1))
+                                .map_err(Error::from)
+                                .boxify()
+                        }
+                        Err(error) => {
+                            // ecobee answered, so it is reachable even if it
+                            // rejected this particular request.
+                            breaker.record_success();
+                            future::err(error).boxify()
+                        }
                     }
+                },
+            ))
+        })
+        .boxify()
+    }
+
+    fn copy_request(template: &Request<Vec<u8>>) -> Request<Vec<u8>> {
+        let mut request = Request::new(template.body().clone());
+        *request.method_mut() = template.method().clone();
+        *request.uri_mut() = template.uri().clone();
+        *request.headers_mut() = template.headers().clone();
+        request
+    }
+
+    /// Sends `request` once, or answers it from the fixtures when replaying.
+    /// ecobee not answering within `timeout` counts as a transient failure.
+    fn send_once<R: DeserializeOwned + Send + 'static>(
+        client: &Client<HttpsConnector<HttpConnector>, Body>,
+        fixtures: Option<Arc<Fixtures>>,
+        request: Request<Vec<u8>>,
+        request_id: RequestId,
+        timeout: Duration,
+    ) -> Box<Future<Item = R, Error = Error> + Send> {
+        // Only the path is logged, the query string may carry credentials.
+        debug!(
+            "[{}] sending {} {}",
+            request_id,
+            request.method(),
+            request.uri().path()
+        );
+
+        if let Some(replayed) = fixtures.as_ref().and_then(|f| f.respond(&request)) {
+            return replayed
+                .and_then(|(status, data)| {
+                    debug!("[{}] replayed a {} response", request_id, status);
+                    Self::parse_response(status, &data[..])
                 })
+                .into_future()
+                .boxify();
+        }
+
+        let response = client
+            .request(Self::copy_request(&request).map(Body::from))
+            .and_then(move |resp| {
+                let status = resp.status();
+                debug!("[{}] ecobee responded with {}", request_id, status);
+                resp.into_body()
+                    .concat2()
+                    .map(move |chunk| (status, chunk.to_vec()))
+            });
+
+        Deadline::new(response, Instant::now() + timeout)
+            .map_err(move |e| -> Error {
+                let error = if e.is_elapsed() {
+                    format_err!("ecobee did not answer within {}s", timeout.as_secs())
+                } else if e.is_inner() {
+                    e.into_inner().expect("inner error").into()
+                } else {
+                    e.into_timer().expect("timer error").into()
+                };
+
+                Transient(error).into()
+            })
+            .and_then(move |(status, data)| {
+                if let Some(fixtures) = fixtures {
+                    fixtures.save(&request, status, &data[..]);
+                }
+                Self::parse_response(status, &data[..])
             })
             .boxify()
     }
 
+    fn parse_response<R: DeserializeOwned>(status: StatusCode, data: &[u8]) -> Result<R> {
+        let mut transient = status.is_server_error();
+
+        // ecobee refuses requests through the status envelope, sometimes with
+        // a 200, so it is checked before the body is trusted.
+        let error: Error = match serde_json::from_slice::<StatusEnvelope>(data) {
+            Ok(envelope) if envelope.status.code != 0 => {
+                transient |= envelope.status.code == PROCESSING_ERROR;
+
+                ApiError::Rejected {
+                    code: envelope.status.code,
+                    message: envelope.status.message,
+                }
+                .into()
+            }
+            _ => {
+                let e = match serde_json::from_slice(data) {
+                    Ok(response) => return Ok(response),
+                    Err(e) => e,
+                };
+
+                if let Ok(message) = serde_json::from_slice::<ErrorMessage>(data) {
+                    ApiError::Upstream {
+                        code: Some(message.error),
+                        message: message.error_description,
+                    }
+                    .into()
+                } else {
+                    e.into()
+                }
+            }
+        };
+
+        if transient {
+            Err(Transient(error).into())
+        } else {
+            Err(error)
+        }
+    }
+
     fn auth(
         &self,
         username: String,
@@ -176,13 +497,14 @@ impl EcobeeActor {
             self.default_request(false).and_then(|mut req| {
                 req.method("POST")
                     .uri(url)
-                    .body(body.into())
+                    .body(body.into_bytes())
                     .map_err(|e| e.into())
             })
         });
 
+        // Authorization grants are single use, so never repeat them.
         match req {
-            Ok(req) => self.send_request(req),
+            Ok(req) => self.send_request(req, false),
             Err(err) => Err(err_msg(format!("failed to build the request: {:?}", err)))
                 .into_future()
                 .boxify(),
@@ -200,35 +522,104 @@ impl EcobeeActor {
             self.default_request(false).and_then(|mut req| {
                 req.method("POST")
                     .uri(url)
-                    .body(Body::empty())
+                    .body(Vec::new())
                     .map_err(|e| e.into())
             })
         });
 
+        // Authorization grants are single use, so never repeat them.
         match req {
-            Ok(req) => self.send_request(req),
+            Ok(req) => self.send_request(req, false),
             Err(err) => Err(err_msg(format!("failed to build the request: {:?}", err)))
                 .into_future()
                 .boxify(),
         }
     }
 
+    /// Exchanges the refresh token kept from the last run for a new token,
+    /// falling back to the password if there is none or ecobee refuses it.
+    fn resume_session(&self) -> Box<Future<Item = AuthToken, Error = Error> + Send> {
+        let login = self.auth(self.username.clone(), self.password.clone());
+        let stored = match self.token_path {
+            Some(ref path) => load_json_state::<Option<AuthToken>>(path, "ecobee token"),
+            None => None,
+        };
+
+        match stored {
+            Some(token) => {
+                info!("resuming the stored ecobee session of {}", self.account);
+                let account = self.account.clone();
+
+                self.refresh_token(token.refresh_token)
+                    .or_else(move |e| {
+                        warn!(
+                            "stored ecobee token of {} was refused, logging in again: {}",
+                            account, e
+                        );
+                        login
+                    })
+                    .boxify()
+            }
+            None => login.boxify(),
+        }
+    }
+
+    /// Writes the token to `token_path`, readable by the owner only. Failures
+    /// are logged, at worst the next start logs in with the password again.
+    fn save_token(&self) {
+        let (path, token) = match (self.token_path.as_ref(), self.auth_token.as_ref()) {
+            (Some(path), Some(token)) => (path, token),
+            _ => return,
+        };
+
+        if let Err(e) = save_json_state(path, token) {
+            warn!(
+                "failed to save the ecobee token of {} to {}: {}",
+                self.account,
+                path.display(),
+                e
+            );
+        }
+    }
+
+    /// Sends a thermostat update, counting it as in flight until it completes
+    /// or is dropped so that shutdown can wait for it.
+    fn send_write(
+        &self,
+        request: Request<Vec<u8>>,
+    ) -> Box<Future<Item = UpdateResponse, Error = Error> + Send> {
+        let guard = InFlight::new(&self.in_flight);
+
+        self.send_request(request, true)
+            .then(move |result: Result<UpdateResponse>| {
+                drop(guard);
+                if let Ok(ref response) = result {
+                    debug!(
+                        "ecobee accepted the update: {} {}",
+                        response.status.code, response.status.message
+                    );
+                }
+                result
+            })
+            .boxify()
+    }
+
     fn get_thermostat(&self) -> impl Future<Item = ThermostatResponse, Error = Error> {
         let payload = [
-            ("json", r#"{"selection":{"includeOemCfg":"true","includeAlerts":"true","includeVersion":"true","includeLocation":"true","selectionType":"registered","includeEvents":"true","includeHouseDetails":"true","includeRuntime":"true","includeNotificationSettings":"true","includeProgram":"true","includeWeather":"true","includePrivacy":"true","includeSecuritySettings":"true","includeSettings":"true","includeExtendedRuntime":"true","includeSensors":"true","includeTechnician":"true"}}"#.into())
+            ("json", r#"{"selection":{"selectionType":"registered","includeAlerts":"true","includeDevice":"true","includeEvents":"true","includeExtendedRuntime":"true","includeLocation":"true","includeProgram":"true","includeRuntime":"true","includeSensors":"true","includeSettings":"true","includeVersion":"true","includeWeather":"true"}}"#.into())
         ];
 
         let req = Self::build_url("/1/thermostat", payload.to_vec()).and_then(|url| {
             self.default_request(true).and_then(|mut req| {
                 req.method("GET")
                     .uri(url)
-                    .body(Body::empty())
+                    .body(Vec::new())
                     .map_err(|e| e.into())
             })
         });
 
         match req {
-            Ok(req) => self.send_request(req),
+            Ok(req) => self.send_request(req, true),
             Err(err) => Err(err_msg(format!("failed to build the request: {:?}", err)))
                 .into_future()
                 .boxify(),
@@ -238,15 +629,8 @@ impl EcobeeActor {
     fn set_hvac_mode(
         &self,
         identifier: String,
-        mode: u8,
+        mode: &str,
     ) -> Box<Future<Item = UpdateResponse, Error = Error> + Send> {
-        let mode = match mode {
-            1 => "heat",
-            2 => "cool",
-            3 => "auto",
-            _ => "off",
-        };
-
         let payload = json!({
             "selection": {
                 "selectionType": "thermostats",
@@ -264,13 +648,123 @@ impl EcobeeActor {
                 self.default_request(true).and_then(|mut req| {
                     req.method("POST")
                         .uri(url)
-                        .body(payload.to_string().into())
+                        .body(payload.to_string().into_bytes())
+                        .map_err(|e| e.into())
+                })
+            });
+
+        match req {
+            Ok(req) => self.send_write(req),
+            Err(err) => Err(err_msg(format!("failed to build the request: {:?}", err)))
+                .into_future()
+                .boxify(),
+        }
+    }
+
+    /// Sets the minimum minutes per hour the fan runs.
+    fn set_fan_min_on_time(
+        &self,
+        identifier: String,
+        minutes: u8,
+    ) -> Box<Future<Item = UpdateResponse, Error = Error> + Send> {
+        let payload = json!({
+            "selection": {
+                "selectionType": "thermostats",
+                "selectionMatch": identifier,
+            },
+            "thermostat": [{
+                "settings": {
+                    "fanMinOnTime": minutes
+                }
+            }]
+        });
+
+        let req =
+            Self::build_url("/1/thermostat?format=json&format=json", Vec::new()).and_then(|url| {
+                self.default_request(true).and_then(|mut req| {
+                    req.method("POST")
+                        .uri(url)
+                        .body(payload.to_string().into_bytes())
+                        .map_err(|e| e.into())
+                })
+            });
+
+        match req {
+            Ok(req) => self.send_write(req),
+            Err(err) => Err(err_msg(format!("failed to build the request: {:?}", err)))
+                .into_future()
+                .boxify(),
+        }
+    }
+
+    /// Holds the comfort setting `climate_ref`.
+    fn set_climate_hold(
+        &self,
+        identifier: String,
+        climate_ref: &str,
+        hold_type: HoldType,
+    ) -> Box<Future<Item = UpdateResponse, Error = Error> + Send> {
+        let payload = json!({
+            "selection": {
+                "selectionType": "thermostats",
+                "selectionMatch": identifier,
+            },
+            "functions": [{
+                "type": "setHold",
+                "params": {
+                    "holdClimateRef": climate_ref,
+                    "holdType": hold_type.ecobee_name()
+                }
+            }]
+        });
+
+        let req =
+            Self::build_url("/1/thermostat?format=json&format=json", Vec::new()).and_then(|url| {
+                self.default_request(true).and_then(|mut req| {
+                    req.method("POST")
+                        .uri(url)
+                        .body(payload.to_string().into_bytes())
                         .map_err(|e| e.into())
                 })
             });
 
         match req {
-            Ok(req) => Box::new(self.send_request(req)),
+            Ok(req) => self.send_write(req),
+            Err(err) => Err(err_msg(format!("failed to build the request: {:?}", err)))
+                .into_future()
+                .boxify(),
+        }
+    }
+
+    fn resume_program(
+        &self,
+        identifier: String,
+    ) -> Box<Future<Item = UpdateResponse, Error = Error> + Send> {
+        let payload = json!({
+            "selection": {
+                "selectionType": "thermostats",
+                "selectionMatch": identifier,
+            },
+            "functions": [{
+                "type": "resumeProgram",
+                "params": {
+                    "resumeAll": true
+                }
+            }]
+        });
+
+        let req =
+            Self::build_url("/1/thermostat?format=json&format=json", Vec::new()).and_then(|url| {
+                self.default_request(true).and_then(|mut req| {
+                    req.method("POST")
+                        .uri(url)
+                        .body(payload.to_string().into_bytes())
+                        .map_err(|e| e.into())
+                })
+            });
+
+        match req {
+            Ok(req) => self.send_write(req),
             Err(err) => Err(err_msg(format!("failed to build the request: {:?}", err)))
                 .into_future()
                 .boxify(),
@@ -280,8 +774,9 @@ impl EcobeeActor {
     fn set_temperature(
         &self,
         identifier: String,
-        heat: u16,
-        cool: u16, /* 770 */
+        heat: Temperature,
+        cool: Temperature,
+        hold_type: HoldType,
     ) -> impl Future<Item = UpdateResponse, Error = Error> {
         let payload = json!({
             "selection": {
@@ -291,33 +786,174 @@ impl EcobeeActor {
             "functions": [{
                 "type": "setHold",
                 "params": {
-                    "heatHoldTemp": heat,
-                    "coolHoldTemp": cool,
-                    "holdType": "indefinite"
+                    "heatHoldTemp": heat.tenths(),
+                    "coolHoldTemp": cool.tenths(),
+                    "holdType": hold_type.ecobee_name()
                 }
             }]
         });
 
-        println!("payload: {:?}", payload);
+        debug!("setting hold: {}", payload);
 
         let req =
             Self::build_url("/1/thermostat?format=json&format=json", Vec::new()).and_then(|url| {
                 self.default_request(true).and_then(|mut req| {
                     req.method("POST")
                         .uri(url)
-                        .body(payload.to_string().into())
+                        .body(payload.to_string().into_bytes())
                         .map_err(|e| e.into())
                 })
             });
 
         match req {
-            Ok(req) => self.send_request(req),
+            Ok(req) => self.send_write(req),
             Err(err) => Err(err_msg(format!("failed to build the request: {:?}", err)))
                 .into_future()
                 .boxify(),
         }
     }
 
+    fn flush_hold(&mut self, identifier: String, addr: Addr<Self>) {
+        if let Some(hold) = self.pending_holds.remove(&identifier) {
+            let PendingHold {
+                heat,
+                cool,
+                hold_type,
+                status,
+                waiters,
+                request_id,
+            } = hold;
+
+            debug!(
+                "[{}] sending coalesced hold for {} request(s)",
+                request_id,
+                waiters.len()
+            );
+
+            // Attribute the write to the request that supplied the final value.
+            self.request_id = Some(request_id);
+            let write = self.set_temperature(identifier.clone(), heat, cool, hold_type);
+            self.request_id = None;
+
+            let expected = Expected {
+                setpoints: Some((heat, cool)),
+                hold: Some(true),
+                ..Expected::default()
+            };
+            let write = write.then(move |result| {
+                if result.is_ok() {
+                    settle_write(addr, identifier, expected);
+                }
+                let result = result.map(|_| status).map_err(ApiError::from);
+
+                for waiter in waiters {
+                    let _ = waiter.send(result.clone());
+                }
+
+                Ok(())
+            });
+
+            Arbiter::spawn(write);
+        }
+    }
+
+    /// When the thermostats were last polled, or why they aren't known yet.
+    fn polled(&self) -> Result<SystemTime> {
+        match self.last_poll {
+            Some(updated) => Ok(updated),
+            None if self.auth_token.is_none() => Err(ApiError::NotAuthenticated.into()),
+            None => Err(ApiError::NoThermostat.into()),
+        }
+    }
+
+    /// The thermostat matching `selector`, or the first one without it.
+    fn select_thermostat(
+        &self,
+        selector: Option<&str>,
+    ) -> ::std::result::Result<&Thermostat, ApiError> {
+        Thermostat::select(&self.thermostats, selector).ok_or_else(|| match selector {
+            Some(selector) => ApiError::UnknownThermostat(selector.to_owned()),
+            None => ApiError::NoThermostat,
+        })
+    }
+
+    fn health(&self) -> HealthStatus {
+        HealthStatus {
+            authenticated: self.auth_token.is_some(),
+            token_age: self
+                .token_updated
+                .map(|updated| updated.elapsed().as_secs()),
+            last_poll: self.last_poll.map(unix_time),
+            last_poll_age: self.last_poll.map(age),
+            last_error: self
+                .last_error
+                .as_ref()
+                .map(|&(at, ref message)| LastError {
+                    at: unix_time(at),
+                    message: message.clone(),
+                }),
+            thermostats: self.thermostats.len(),
+            circuit: self.breaker.describe(),
+            pending_changes: self.pending_changes.len(),
+            conflicts: {
+                let mut conflicts = self.conflicts.values().cloned().collect::<Vec<_>>();
+                conflicts.sort_by(|a, b| a.thermostat.cmp(&b.thermostat));
+                conflicts
+            },
+        }
+    }
+
+    /// Checks freshly polled thermostats against the writes they should
+    /// reflect. Polls right after a write often still show the old state, so
+    /// the write stays applied until the polls agree or the timeout runs out.
+    fn reconcile(&mut self) {
+        let timeout = Duration::from_secs(RECONCILE_TIMEOUT_SECS);
+        let mut settled = Vec::new();
+
+        for thermostat in &mut self.thermostats {
+            let pending = match self.pending_changes.get(&thermostat.identifier) {
+                Some(pending) => pending,
+                None => continue,
+            };
+            let mismatches = pending.expected.mismatches(thermostat);
+
+            if mismatches.is_empty() {
+                debug!(
+                    "ecobee confirmed the change to {} of {}",
+                    thermostat.identifier, self.account
+                );
+                self.conflicts.remove(&thermostat.identifier);
+                settled.push(thermostat.identifier.clone());
+            } else if pending.since.elapsed() < timeout {
+                pending.expected.apply(thermostat);
+            } else {
+                warn!(
+                    "ecobee still reports a different {} for {} of {} than was set, keeping ecobee's state",
+                    mismatches.join(", "),
+                    thermostat.identifier,
+                    self.account
+                );
+                self.conflicts.insert(
+                    thermostat.identifier.clone(),
+                    Conflict::new(&thermostat.identifier, mismatches),
+                );
+                settled.push(thermostat.identifier.clone());
+            }
+        }
+
+        for identifier in settled {
+            self.pending_changes.remove(&identifier);
+        }
+
+        // Thermostats removed from the account won't confirm anything.
+        let thermostats = &self.thermostats;
+        self.pending_changes.retain(|identifier, _| {
+            thermostats
+                .iter()
+                .any(|thermostat| thermostat.identifier == *identifier)
+        });
+    }
+
     fn default_request(&self, auth: bool) -> Result<Builder> {
         let mut builder = Request::builder();
 
@@ -329,10 +965,7 @@ impl EcobeeActor {
             .header("X-ECOBEE-APP", "ecobee-ios");
 
         if auth {
-            let token = self
-                .auth_token
-                .clone()
-                .ok_or_else(|| err_msg("auth token is not set yet"))?;
+            let token = self.auth_token.clone().ok_or(ApiError::NotAuthenticated)?;
             let value = format!("Bearer {}", token.access_token);
 
             builder.header("Authorization", &value[..]);
@@ -346,17 +979,18 @@ impl Actor for EcobeeActor {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
-        let username = self.username.clone();
-        let password = self.password.clone();
         let addr = ctx.address();
+        let error_addr = ctx.address();
+        let account = self.account.clone();
         let auth = self
-            .auth(username, password)
+            .resume_session()
             .and_then(move |token| {
                 addr.try_send(SetAuthToken(token))
                     .map_err(|_| err_msg("send error"))
             })
-            .map_err(|err| {
-                println!("{}", err);
+            .map_err(move |err| {
+                error!("failed to authenticate ecobee account {}: {}", account, err);
+                error_addr.do_send(RecordError(err.to_string()));
             });
 
         Arbiter::spawn(auth);
@@ -364,71 +998,88 @@ impl Actor for EcobeeActor {
         ctx.run_interval(Duration::from_secs(60 * 60 * 24), |actor, context| {
             if let Some(token) = actor.auth_token.clone() {
                 let addr = context.address();
-                println!("refreshing token...");
+                let error_addr = context.address();
+                let account = actor.account.clone();
+                info!("refreshing ecobee token of {}", account);
                 let refresh = actor
                     .refresh_token(token.refresh_token)
                     .map(move |token| {
-                        if let Err(_) = addr.try_send(SetAuthToken(token)) {
-                            eprintln!("send failed.");
+                        if addr.try_send(SetAuthToken(token)).is_err() {
+                            error!("failed to store the refreshed token");
                         }
                     })
-                    .map_err(|e| {
-                        eprintln!("error occurred when refreshing token: {:?}", e);
+                    .map_err(move |e| {
+                        error!("error occurred when refreshing token of {}: {}", account, e);
+                        error_addr.do_send(RecordError(e.to_string()));
                     });
 
                 Arbiter::spawn(refresh);
             }
         });
 
-        ctx.run_interval(Duration::from_secs(30), |actor, context| {
-            let addr = context.address();
-            let fut = actor
-                .get_thermostat()
-                .map(move |thermostat| {
-                    if let Err(_) = addr.try_send(UpdateThermostat(thermostat)) {
-                        eprintln!("send failed.");
-                    }
-                })
-                .map_err(|e| {
-                    eprintln!("error occurred when fetching thermostat: {:?}", e);
-                });
+        self.schedule_poll(ctx);
 
-            Arbiter::spawn(fut);
+        // Schedules wait for the first poll, which tells their time zone.
+        ctx.run_interval(Duration::from_secs(schedules::TICK), |actor, ctx| {
+            for firing in actor.schedules.tick(&actor.thermostats) {
+                rules::dispatch(ctx.address(), firing);
+            }
         });
     }
 }
 
-impl Handler<EcobeeQuery> for EcobeeActor {
-    type Result = Result<EcobeeResponse>;
+impl Handler<StatusQuery> for EcobeeActor {
+    type Result = Result<ThermostatStatus>;
 
-    fn handle(&mut self, _query: EcobeeQuery, _ctx: &mut Self::Context) -> Self::Result {
-        if let Some(thermostat) = self.thermostats.first() {
-            let mode: u8 = match &thermostat.settings.hvac_mode[..] {
-                "auto" => 3,
-                "cool" => 2,
-                "heat" => 1,
-                _ => 0,
-            };
-            let runtime = &thermostat.runtime;
-            let target: f32 = {
-                let heat = runtime.desired_heat as f32;
-                let cool = runtime.desired_cool as f32;
-                (heat + cool) / 20.0
-            };
-            let current: f32 = (runtime.temperature as f32) / 10.0;
-            let humidity: f32 = runtime.humidity as f32;
-            let target_humidity: f32 = runtime.desired_humidity as f32;
-
-            Ok(EcobeeResponse::Status(EcobeeStatus::new(
-                mode,
-                ftoc(target),
-                ftoc(current).round(),
-                humidity,
-                target_humidity / 100.0,
-            )))
-        } else {
-            Err(err_msg("no thermostat available"))
-        }
+    fn handle(&mut self, query: StatusQuery, _: &mut Self::Context) -> Self::Result {
+        let updated = self.polled()?;
+        let thermostat = self.select_thermostat(query.0.as_deref())?;
+
+        Ok(thermostat.status(updated))
+    }
+}
+
+impl Handler<RawQuery> for EcobeeActor {
+    type Result = Result<Thermostat>;
+
+    fn handle(&mut self, query: RawQuery, _: &mut Self::Context) -> Self::Result {
+        self.polled()?;
+
+        Ok(self.select_thermostat(Some(&query.0))?.clone())
+    }
+}
+
+impl Handler<ThermostatsQuery> for EcobeeActor {
+    type Result = Result<Vec<ThermostatSummary>>;
+
+    fn handle(&mut self, _: ThermostatsQuery, _: &mut Self::Context) -> Self::Result {
+        self.polled()?;
+
+        Ok(self.thermostats.iter().map(Thermostat::summary).collect())
+    }
+}
+
+impl Handler<HealthQuery> for EcobeeActor {
+    type Result = MessageResult<HealthQuery>;
+
+    fn handle(&mut self, _: HealthQuery, _: &mut Self::Context) -> Self::Result {
+        MessageResult(self.health())
+    }
+}
+
+impl Handler<RulesQuery> for EcobeeActor {
+    type Result = MessageResult<RulesQuery>;
+
+    fn handle(&mut self, _: RulesQuery, _: &mut Self::Context) -> Self::Result {
+        MessageResult(self.rules.report())
+    }
+}
+
+impl Handler<SchedulesQuery> for EcobeeActor {
+    type Result = MessageResult<SchedulesQuery>;
+
+    fn handle(&mut self, _: SchedulesQuery, _: &mut Self::Context) -> Self::Result {
+        MessageResult(self.schedules.report(&self.thermostats))
     }
 }
 
@@ -438,8 +1089,153 @@ struct UpdateThermostat(ThermostatResponse);
 impl Handler<UpdateThermostat> for EcobeeActor {
     type Result = ();
 
-    fn handle(&mut self, update: UpdateThermostat, _: &mut Self::Context) -> Self::Result {
+    fn handle(&mut self, update: UpdateThermostat, ctx: &mut Self::Context) -> Self::Result {
         self.thermostats = update.0.thermostats;
+        self.last_poll = Some(SystemTime::now());
+        self.reconcile();
+
+        for firing in self.rules.evaluate(&self.thermostats) {
+            rules::dispatch(ctx.address(), firing);
+        }
+    }
+}
+
+impl Handler<FiringOutcome> for EcobeeActor {
+    type Result = ();
+
+    fn handle(&mut self, outcome: FiringOutcome, _: &mut Self::Context) -> Self::Result {
+        match outcome.trigger {
+            Trigger::Rule => self.rules.record_outcome(outcome),
+            Trigger::Schedule => self.schedules.record_outcome(outcome),
+        }
+    }
+}
+
+/// A write ecobee accepted, to show in the cache before polls reflect it.
+#[derive(Message)]
+struct ChangeApplied {
+    identifier: String,
+    expected: Expected,
+}
+
+impl Handler<ChangeApplied> for EcobeeActor {
+    type Result = ();
+
+    fn handle(&mut self, change: ChangeApplied, _: &mut Self::Context) -> Self::Result {
+        let ChangeApplied {
+            identifier,
+            expected,
+        } = change;
+
+        if let Some(thermostat) = self
+            .thermostats
+            .iter_mut()
+            .find(|thermostat| thermostat.identifier == identifier)
+        {
+            expected.apply(thermostat);
+        }
+
+        match self.pending_changes.entry(identifier) {
+            Entry::Occupied(mut entry) => {
+                let pending = entry.get_mut();
+                pending.expected.merge(expected);
+                pending.since = Instant::now();
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(PendingChange {
+                    expected,
+                    since: Instant::now(),
+                });
+            }
+        }
+    }
+}
+
+impl Handler<RefreshNow> for EcobeeActor {
+    type Result = Result<Box<Future<Item = (), Error = Error> + Send + 'static>>;
+
+    fn handle(&mut self, _: RefreshNow, ctx: &mut Self::Context) -> Self::Result {
+        Ok(self
+            .refresh(ctx)
+            .map(|_| ())
+            .map_err(|e| match e.downcast_ref::<ApiError>() {
+                // Keep typed errors typed for the HTTP API.
+                Some(error) => error.clone().into(),
+                None => err_msg(e.to_string()),
+            })
+            .boxify())
+    }
+}
+
+#[derive(Message)]
+struct RefreshDone;
+
+impl Handler<RefreshDone> for EcobeeActor {
+    type Result = ();
+
+    fn handle(&mut self, _: RefreshDone, _: &mut Self::Context) -> Self::Result {
+        self.refreshing = None;
+    }
+}
+
+impl Handler<Drain> for EcobeeActor {
+    type Result = Result<Box<Future<Item = (), Error = Error> + Send + 'static>>;
+
+    fn handle(&mut self, _: Drain, ctx: &mut Self::Context) -> Self::Result {
+        let pending = self.pending_holds.keys().cloned().collect::<Vec<_>>();
+        for identifier in pending {
+            self.flush_hold(identifier, ctx.address());
+        }
+        self.save_token();
+
+        let in_flight = self.in_flight.clone();
+
+        Ok(future::loop_fn((), move |_| {
+            if in_flight.load(Ordering::SeqCst) == 0 {
+                Either::A(future::ok(Loop::Break(())))
+            } else {
+                Either::B(
+                    Delay::new(Instant::now() + Duration::from_millis(50))
+                        .map(Loop::Continue)
+                        .from_err(),
+                )
+            }
+        })
+        .boxify())
+    }
+}
+
+impl Handler<Reconfigure> for EcobeeActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: Reconfigure, ctx: &mut Context<Self>) {
+        let config = msg.0;
+
+        self.rules.reconfigure(&config);
+        self.schedules.reconfigure(&config);
+        if config.retry != self.retry {
+            self.breaker = CircuitBreaker::new(&config.retry);
+            self.retry = config.retry;
+        }
+        self.limiter = TokenBucket::new(&config.rate_limit);
+        self.coalesce_window = Duration::from_millis(config.rate_limit.coalesce_window_ms);
+
+        let poll_interval = Duration::from_secs(config.poll_interval);
+        if poll_interval != self.poll_interval {
+            self.poll_interval = poll_interval;
+            self.schedule_poll(ctx);
+        }
+    }
+}
+
+#[derive(Message)]
+struct RecordError(String);
+
+impl Handler<RecordError> for EcobeeActor {
+    type Result = ();
+
+    fn handle(&mut self, error: RecordError, _: &mut Self::Context) -> Self::Result {
+        self.last_error = Some((SystemTime::now(), error.0));
     }
 }
 
@@ -450,43 +1246,581 @@ impl Handler<SetAuthToken> for EcobeeActor {
     type Result = ();
 
     fn handle(&mut self, request: SetAuthToken, _: &mut Self::Context) -> Self::Result {
-        println!("setting token to: {:?}", request.0);
+        info!("received ecobee token of {}", self.account);
+        debug!("token: {:?}", request.0);
         self.auth_token = Some(request.0.clone());
+        self.token_updated = Some(Instant::now());
+        self.save_token();
     }
 }
 
-pub enum ChangeThermostat {
-    HvacMode(u8),
-    Temperature(f32),
+impl Handler<Traced<ChangeThermostat>> for EcobeeActor {
+    type Result = <EcobeeActor as Handler<ChangeThermostat>>::Result;
+
+    fn handle(
+        &mut self,
+        traced: Traced<ChangeThermostat>,
+        ctx: &mut Self::Context,
+    ) -> Self::Result {
+        self.request_id = Some(traced.request_id);
+        let result = self.handle(traced.message, ctx);
+        self.request_id = None;
+        result
+    }
 }
 
-impl Message for ChangeThermostat {
-    type Result = Result<Box<Future<Item = (), Error = Error> + Send + 'static>>;
+impl Handler<Traced<TargetedChange>> for EcobeeActor {
+    type Result = <EcobeeActor as Handler<TargetedChange>>::Result;
+
+    fn handle(&mut self, traced: Traced<TargetedChange>, ctx: &mut Self::Context) -> Self::Result {
+        self.request_id = Some(traced.request_id);
+        let result = self.handle(traced.message, ctx);
+        self.request_id = None;
+        result
+    }
+}
+
+impl Handler<Traced<RefreshNow>> for EcobeeActor {
+    type Result = <EcobeeActor as Handler<RefreshNow>>::Result;
+
+    fn handle(&mut self, traced: Traced<RefreshNow>, ctx: &mut Self::Context) -> Self::Result {
+        self.request_id = Some(traced.request_id);
+        let result = self.handle(traced.message, ctx);
+        self.request_id = None;
+        result
+    }
+}
+
+impl Backend for EcobeeActor {
+    fn backend_addr(addr: Addr<Self>) -> BackendAddr {
+        BackendAddr::Ecobee(addr)
+    }
+}
+
+/// Applies a write ecobee just accepted to the cache right away, then polls
+/// ecobee in the background once it had a moment to settle, so the cache is
+/// confirmed instead of waiting for the next scheduled poll.
+fn settle_write(addr: Addr<EcobeeActor>, identifier: String, expected: Expected) {
+    addr.do_send(ChangeApplied {
+        identifier,
+        expected,
+    });
+
+    let refresh = Delay::new(Instant::now() + Duration::from_millis(WRITE_SETTLE_MS))
+        .map_err(Error::from)
+        .and_then(move |_| addr.send(RefreshNow).map_err(|_| err_msg("mailbox error")))
+        .and_then(|resp| resp)
+        .and_then(|fut| fut)
+        // Fetch failures are already logged and recorded by the refresh.
+        .map_err(|e| debug!("failed to refresh after a write: {}", e));
+
+    Arbiter::spawn(refresh);
+}
+
+/// A temperature hold waiting for its coalescing window to close.
+struct PendingHold {
+    heat: Temperature,
+    cool: Temperature,
+    hold_type: HoldType,
+    status: ThermostatStatus,
+    waiters: Vec<oneshot::Sender<::std::result::Result<ThermostatStatus, ApiError>>>,
+    request_id: RequestId,
+}
+
+impl PendingHold {
+    /// Replaces the setpoints with the newer `hold`, keeping every waiter.
+    fn replace(&mut self, hold: PendingHold) {
+        self.heat = hold.heat;
+        self.cool = hold.cool;
+        self.hold_type = hold.hold_type;
+        self.status = hold.status;
+        self.waiters.extend(hold.waiters);
+        self.request_id = hold.request_id;
+    }
+}
+
+/// Counts a write as in flight for as long as it is alive.
+struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    fn new(counter: &Arc<AtomicUsize>) -> Self {
+        counter.fetch_add(1, Ordering::SeqCst);
+        InFlight(counter.clone())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 impl Handler<ChangeThermostat> for EcobeeActor {
-    type Result = Result<Box<Future<Item = (), Error = Error> + Send + 'static>>;
+    type Result = <EcobeeActor as Handler<TargetedChange>>::Result;
+
+    fn handle(&mut self, change: ChangeThermostat, ctx: &mut Self::Context) -> Self::Result {
+        self.handle(
+            TargetedChange {
+                thermostat: None,
+                change,
+            },
+            ctx,
+        )
+    }
+}
+
+impl Handler<TargetedChange> for EcobeeActor {
+    type Result = Result<Box<Future<Item = ThermostatStatus, Error = Error> + Send + 'static>>;
+
+    fn handle(&mut self, targeted: TargetedChange, ctx: &mut Self::Context) -> Self::Result {
+        if self.auth_token.is_none() {
+            return Err(ApiError::NotAuthenticated.into());
+        }
+
+        let TargetedChange {
+            thermostat: selector,
+            change: request,
+        } = targeted;
+        let thermostat = self.select_thermostat(selector.as_deref())?;
+
+        // The returned status optimistically reflects the change, so callers
+        // don't have to wait for the next poll to see it.
+        let status = thermostat.status(self.last_poll.unwrap_or_else(SystemTime::now));
+
+        match request {
+            ChangeThermostat::HvacMode(mode) => {
+                let name = hvac_mode_name(mode).ok_or_else(|| {
+                    ApiError::Validation(format!(
+                        "unknown heating/cooling state {}, expected 0 (off), 1 (heat), 2 (cool) or 3 (auto)",
+                        mode
+                    ))
+                })?;
+
+                let addr = ctx.address();
+                let identifier = thermostat.identifier.clone();
+                let expected = Expected {
+                    hvac_mode: Some(name.to_owned()),
+                    ..Expected::default()
+                };
+
+                Ok(self
+                    .set_hvac_mode(identifier.clone(), name)
+                    .map(move |_| {
+                        settle_write(addr, identifier, expected);
+                        status.with_target_heating_cooling_state(mode)
+                    })
+                    .boxify())
+            }
+            ChangeThermostat::Temperature(temperature, hold_type) => {
+                let (heat, cool) = thermostat.settings.hold_setpoints(temperature)?;
+                let status = status.with_target_temperature(temperature);
+
+                if self.coalesce_window == Duration::from_secs(0) {
+                    let addr = ctx.address();
+                    let identifier = thermostat.identifier.clone();
+                    let expected = Expected {
+                        setpoints: Some((heat, cool)),
+                        hold: Some(true),
+                        ..Expected::default()
+                    };
+
+                    return Ok(self
+                        .set_temperature(identifier.clone(), heat, cool, hold_type)
+                        .map(move |_| {
+                            settle_write(addr, identifier, expected);
+                            status
+                        })
+                        .boxify());
+                }
 
-    fn handle(&mut self, request: ChangeThermostat, _: &mut Self::Context) -> Self::Result {
-        if let Some(thermostat) = self.thermostats.first() {
-            match request {
-                ChangeThermostat::HvacMode(mode) => Ok(self
-                    .set_hvac_mode(thermostat.identifier.clone(), mode)
-                    .map(|_| ())
-                    .boxify()),
-                ChangeThermostat::Temperature(temperature) => {
-                    let temperature = (ctof(temperature) * 10.0) as u16;
-                    let heat = temperature - 36;
-                    let cool = temperature + 36;
-
-                    Ok(self
-                        .set_temperature(thermostat.identifier.clone(), heat, cool)
-                        .map(|_| ())
-                        .boxify())
+                // Slider drags fire many changes in a row. Collect them for
+                // one window and only send the last value; every caller in
+                // the window gets the result of that single write.
+                let (sender, receiver) = oneshot::channel();
+                let hold = PendingHold {
+                    heat,
+                    cool,
+                    hold_type,
+                    status,
+                    waiters: vec![sender],
+                    request_id: self.request_id.clone().unwrap_or_else(RequestId::generate),
+                };
+
+                match self.pending_holds.entry(thermostat.identifier.clone()) {
+                    Entry::Occupied(mut entry) => entry.get_mut().replace(hold),
+                    Entry::Vacant(entry) => {
+                        let identifier = entry.key().clone();
+                        ctx.run_later(self.coalesce_window, move |actor, ctx| {
+                            actor.flush_hold(identifier, ctx.address())
+                        });
+                        entry.insert(hold);
+                    }
                 }
+
+                Ok(receiver
+                    .map_err(|_| err_msg("pending temperature change was dropped"))
+                    .and_then(|result| result.map_err(Error::from))
+                    .boxify())
             }
-        } else {
-            Err(err_msg("no thermostat available"))
+            ChangeThermostat::Climate(climate_ref, hold_type) => {
+                let climate = thermostat.climate(&climate_ref)?;
+                let status = status.with_target_temperature(Temperature::from_tenths(
+                    (climate.heat_temp.tenths() + climate.cool_temp.tenths()) / 2,
+                ));
+                let addr = ctx.address();
+                let identifier = thermostat.identifier.clone();
+                let expected = Expected {
+                    setpoints: Some((climate.heat_temp, climate.cool_temp)),
+                    hold: Some(true),
+                    climate: Some(climate_ref.clone()),
+                    ..Expected::default()
+                };
+
+                Ok(self
+                    .set_climate_hold(identifier.clone(), &climate_ref, hold_type)
+                    .map(move |_| {
+                        settle_write(addr, identifier, expected);
+                        status
+                    })
+                    .boxify())
+            }
+            ChangeThermostat::FanMinOnTime(minutes) => {
+                if minutes > MAX_FAN_MIN_ON_TIME {
+                    return Err(ApiError::Validation(format!(
+                        "the fan can run at most {} minutes per hour, not {}",
+                        MAX_FAN_MIN_ON_TIME, minutes
+                    ))
+                    .into());
+                }
+
+                let addr = ctx.address();
+                let identifier = thermostat.identifier.clone();
+                let expected = Expected {
+                    fan_min_on_time: Some(i32::from(minutes)),
+                    ..Expected::default()
+                };
+
+                Ok(self
+                    .set_fan_min_on_time(identifier.clone(), minutes)
+                    .map(move |_| {
+                        settle_write(addr, identifier, expected);
+                        status
+                    })
+                    .boxify())
+            }
+            ChangeThermostat::ResumeProgram => {
+                // The resulting setpoints come from the program, so the
+                // status has to be fetched again rather than predicted.
+                let identifier = thermostat.identifier.clone();
+                let refresh_addr = ctx.address();
+                let query_addr = ctx.address();
+
+                let applied_identifier = identifier.clone();
+
+                Ok(self
+                    .resume_program(identifier.clone())
+                    .and_then(move |_| {
+                        refresh_addr.do_send(ChangeApplied {
+                            identifier: applied_identifier,
+                            expected: Expected {
+                                hold: Some(false),
+                                ..Expected::default()
+                            },
+                        });
+                        refresh_addr
+                            .send(RefreshNow)
+                            .map_err(|_| err_msg("mailbox error"))
+                    })
+                    .and_then(|refresh| refresh)
+                    .and_then(|fut| fut)
+                    .and_then(move |_| {
+                        query_addr
+                            .send(StatusQuery(Some(identifier)))
+                            .map_err(|_| err_msg("mailbox error"))
+                    })
+                    .and_then(|resp| resp)
+                    .boxify())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Read;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+    use std::sync::Mutex;
+
+    use actix::System;
+    use serde_json::Value;
+
+    use config::DEFAULT_ACCOUNT;
+    use fixtures::FixtureMode;
+    use temperature::Units;
+
+    /// Scrubbed ecobee traffic of an account named `default`, replayable with
+    /// `castform --replay fixtures`.
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures");
+
+    /// The response body of a fixture file, as ecobee sent it.
+    fn recorded_body(name: &str) -> Vec<u8> {
+        let path = PathBuf::from(FIXTURES).join(DEFAULT_ACCOUNT).join(name);
+        let mut contents = String::new();
+        File::open(&path)
+            .and_then(|mut file| file.read_to_string(&mut contents))
+            .expect("fixture");
+        let exchange: Value = serde_json::from_str(&contents).expect("fixture json");
+
+        exchange["response"]["body"].to_string().into_bytes()
+    }
+
+    /// The token stored at `path`, read the way `resume_session` does.
+    fn stored_token(path: &Path) -> Option<AuthToken> {
+        load_json_state(path, "ecobee token")
+    }
+
+    #[test]
+    fn parses_recorded_thermostats() {
+        let response: ThermostatResponse = EcobeeActor::parse_response(
+            StatusCode::OK,
+            &recorded_body("0002-GET-1_thermostat.json"),
+        )
+        .expect("thermostat response");
+
+        assert_eq!(response.thermostats.len(), 2);
+        let main = &response.thermostats[0];
+        assert_eq!(main.identifier, "411921234567");
+        assert_eq!(main.settings.hvac_mode, "heat");
+        assert!(main.settings.use_celsius);
+        assert_eq!(
+            main.runtime.actual_temperature,
+            Temperature::from_tenths(712)
+        );
+        assert_eq!(main.settings.heat_range_low, Temperature::from_tenths(450));
+        assert_eq!(main.events[0].kind, "hold");
+        assert!(main.events[0].running);
+        assert_eq!(
+            main.climate("away").expect("away").heat_temp,
+            Temperature::from_tenths(620)
+        );
+        assert_eq!(
+            main.location
+                .as_ref()
+                .map(|location| location.time_zone.as_str()),
+            Some("America/Toronto")
+        );
+        assert_eq!(response.thermostats[1].name, "Upstairs");
+    }
+
+    #[test]
+    fn thermostats_need_the_fields_castform_reads() {
+        let parse = |section: &str, field: &str| {
+            let mut body: Value =
+                serde_json::from_slice(&recorded_body("0002-GET-1_thermostat.json"))
+                    .expect("fixture json");
+            body["thermostatList"][0][section]
+                .as_object_mut()
+                .expect("section")
+                .remove(field)
+                .expect("field");
+
+            EcobeeActor::parse_response::<ThermostatResponse>(
+                StatusCode::OK,
+                body.to_string().as_bytes(),
+            )
+        };
+
+        for &(section, field) in &[
+            ("settings", "hvacMode"),
+            ("settings", "useCelsius"),
+            ("settings", "coolRangeLow"),
+            ("runtime", "actualTemperature"),
+            ("runtime", "desiredHeat"),
+        ] {
+            assert!(parse(section, field).is_err(), "{}.{}", section, field);
         }
+        assert!(parse("settings", "fanMinOnTime").is_ok());
+        assert!(parse("runtime", "actualHumidity").is_ok());
+    }
+
+    #[test]
+    fn parses_recorded_write_responses() {
+        EcobeeActor::parse_response::<UpdateResponse>(
+            StatusCode::OK,
+            &recorded_body("0003-POST-1_thermostat.json"),
+        )
+        .expect("accepted write");
+
+        let error = EcobeeActor::parse_response::<UpdateResponse>(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &recorded_body("0004-POST-1_thermostat.json"),
+        )
+        .expect_err("processing error");
+        let Transient(error) = error.downcast::<Transient>().expect("transient");
+        match error.downcast::<ApiError>().expect("api error") {
+            ApiError::Rejected { code: 3, .. } => (),
+            other => panic!("unexpected error: {}", other),
+        }
+    }
+
+    /// Waits until the replayed login went through.
+    fn authenticated(addr: Addr<EcobeeActor>) -> impl Future<Item = (), Error = Error> {
+        future::loop_fn(addr, |addr| {
+            addr.send(HealthQuery)
+                .map_err(|_| err_msg("mailbox error"))
+                .and_then(move |health| {
+                    if health.authenticated {
+                        Either::A(future::ok(Loop::Break(())))
+                    } else {
+                        Either::B(
+                            Delay::new(Instant::now() + Duration::from_millis(10))
+                                .map(move |_| Loop::Continue(addr))
+                                .from_err(),
+                        )
+                    }
+                })
+        })
+    }
+
+    fn change(
+        addr: &Addr<EcobeeActor>,
+        change: ChangeThermostat,
+    ) -> impl Future<Item = ThermostatStatus, Error = Error> {
+        addr.send(Traced::new(RequestId::generate(), change))
+            .map_err(|_| err_msg("mailbox error"))
+            .and_then(|resp| resp)
+            .and_then(|fut| fut)
+    }
+
+    #[test]
+    fn replays_status_and_writes() {
+        let results = Arc::new(Mutex::new(Vec::new()));
+        let recorded = results.clone();
+
+        System::run(move || {
+            let config: Config =
+                ::toml::from_str("client_id = \"id\"\nusername = \"me\"\npassword = \"secret\"")
+                    .expect("config");
+            let account = config.accounts().remove(0);
+            let fixtures = Fixtures::open(&FixtureMode::Replay(FIXTURES.into()), DEFAULT_ACCOUNT)
+                .expect("fixtures");
+            let addr = EcobeeActor::from_config(&config, &account, Some(fixtures))
+                .expect("actor")
+                .start();
+            let refresh = addr.clone();
+            let query = addr.clone();
+            let write = addr.clone();
+            let rejected = addr.clone();
+            let log = |results: &Arc<Mutex<Vec<String>>>, entry: String| {
+                results.lock().expect("results").push(entry)
+            };
+            let (r1, r2, r3) = (recorded.clone(), recorded.clone(), recorded.clone());
+
+            Arbiter::spawn(
+                authenticated(addr)
+                    .and_then(move |_| {
+                        refresh
+                            .send(Traced::new(RequestId::generate(), RefreshNow))
+                            .map_err(|_| err_msg("mailbox error"))
+                            .and_then(|resp| resp)
+                            .and_then(|fut| fut)
+                    })
+                    .and_then(move |_| {
+                        query
+                            .send(StatusQuery(Some("upstairs".to_owned())))
+                            .map_err(|_| err_msg("mailbox error"))
+                            .and_then(|resp| resp)
+                    })
+                    .map(move |status| {
+                        log(
+                            &r1,
+                            serde_json::to_string(&status.in_units(Units::Celsius))
+                                .expect("status json"),
+                        )
+                    })
+                    .and_then(move |_| change(&write, ChangeThermostat::HvacMode(2)))
+                    .map(move |status| {
+                        log(
+                            &r2,
+                            serde_json::to_string(&status.in_units(Units::Celsius))
+                                .expect("status json"),
+                        )
+                    })
+                    .and_then(move |_| change(&rejected, ChangeThermostat::FanMinOnTime(20)))
+                    .then(move |result| {
+                        match result {
+                            Ok(_) => log(&r3, "fan accepted".to_owned()),
+                            Err(e) => log(&r3, format!("fan: {}", ApiError::from(e))),
+                        }
+                        System::current().stop();
+                        Ok(())
+                    }),
+            );
+        });
+
+        let results = results.lock().expect("results");
+        assert_eq!(results.len(), 3, "{:?}", *results);
+        let upstairs: Value = serde_json::from_str(&results[0]).expect("status");
+        assert_eq!(upstairs["targetHeatingCoolingState"], 2);
+        assert_eq!(upstairs["currentTemperature"], 24.0);
+        assert_eq!(upstairs["currentRelativeHumidity"], 47.0);
+        let changed: Value = serde_json::from_str(&results[1]).expect("status");
+        assert_eq!(changed["targetHeatingCoolingState"], 2);
+        assert_eq!(changed["currentTemperature"], 22.0);
+        assert_eq!(
+            results[2],
+            "fan: ecobee rejected the request: Processing error. Error occurred during the update. (status 3)"
+        );
+    }
+
+    fn actor(state_dir: &Path) -> EcobeeActor {
+        let config: Config = ::toml::from_str(&format!(
+            "state_dir = {:?}",
+            state_dir.display().to_string()
+        ))
+        .expect("config");
+        let account = config.accounts().remove(0);
+
+        EcobeeActor::from_config(&config, &account, None).expect("actor")
+    }
+
+    #[test]
+    fn token_survives_a_restart() {
+        let dir = env::temp_dir().join(format!("castform-token-{}", ::std::process::id()));
+        let mut actor = actor(&dir);
+        actor.auth_token = Some(AuthToken {
+            access_token: "access".to_owned(),
+            refresh_token: "refresh".to_owned(),
+        });
+        actor.save_token();
+
+        let path = dir.join(format!("{}-token.json", DEFAULT_ACCOUNT));
+        let stored = stored_token(&path).expect("stored token");
+        let mode = fs::metadata(&path)
+            .expect("token file")
+            .permissions()
+            .mode();
+        fs::remove_dir_all(&dir).expect("cleanup");
+
+        assert_eq!(stored.refresh_token, "refresh");
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn unreadable_token_is_ignored() {
+        let dir = env::temp_dir().join(format!("castform-bad-token-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).expect("state dir");
+        let path = dir.join("broken-token.json");
+        fs::write(&path, "not json").expect("token file");
+
+        let stored = stored_token(&path);
+        fs::remove_dir_all(&dir).expect("cleanup");
+
+        assert!(stored.is_none());
+        assert!(stored_token(&dir.join("missing-token.json")).is_none());
     }
 }
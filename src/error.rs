@@ -0,0 +1,125 @@
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use failure::Error;
+use serde_json::Value;
+
+use config::Scope;
+
+/// Errors surfaced to HTTP API callers. Each variant maps to a status code
+/// and is rendered as `{"code", "message", "details"}`.
+#[derive(Debug, Clone, Fail)]
+pub enum ApiError {
+    /// No or wrong credentials for castform's own HTTP API.
+    #[fail(display = "missing or invalid credentials")]
+    Unauthorized,
+    #[fail(
+        display = "the `{}` scope cannot make requests needing `{}`",
+        scope, required
+    )]
+    Forbidden { scope: Scope, required: Scope },
+    #[fail(display = "not authenticated with ecobee yet")]
+    NotAuthenticated,
+    #[fail(display = "no thermostat available")]
+    NoThermostat,
+    #[fail(display = "no ecobee account named `{}`", _0)]
+    UnknownAccount(String),
+    #[fail(display = "no thermostat with identifier or name `{}`", _0)]
+    UnknownThermostat(String),
+    #[fail(display = "`{}` is not in presence.people", _0)]
+    UnknownPerson(String),
+    #[fail(display = "ecobee error: {}", message)]
+    Upstream {
+        code: Option<String>,
+        message: String,
+    },
+    /// A non-zero `status.code` in an ecobee response.
+    #[fail(display = "ecobee rejected the request: {} (status {})", message, code)]
+    Rejected { code: i64, message: String },
+    #[fail(display = "invalid request: {}", _0)]
+    Validation(String),
+    #[fail(display = "mailbox error")]
+    Mailbox,
+    #[fail(display = "thermostat data is stale: {}", _0)]
+    Stale(String),
+    #[fail(display = "ecobee is unavailable, circuit breaker is open")]
+    CircuitOpen,
+}
+
+impl ApiError {
+    fn code(&self) -> &'static str {
+        match *self {
+            ApiError::Unauthorized => "unauthorized",
+            ApiError::Forbidden { .. } => "forbidden",
+            ApiError::NotAuthenticated => "not_authenticated",
+            ApiError::NoThermostat => "no_thermostat",
+            ApiError::UnknownAccount(_) => "unknown_account",
+            ApiError::UnknownThermostat(_) => "unknown_thermostat",
+            ApiError::UnknownPerson(_) => "unknown_person",
+            ApiError::Upstream { .. } => "upstream_error",
+            ApiError::Rejected { .. } => "ecobee_rejected",
+            ApiError::Validation(_) => "validation_failed",
+            ApiError::Mailbox => "mailbox_error",
+            ApiError::Stale(_) => "stale_data",
+            ApiError::CircuitOpen => "circuit_open",
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match *self {
+            ApiError::Unauthorized | ApiError::NotAuthenticated => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden { .. } => StatusCode::FORBIDDEN,
+            ApiError::NoThermostat
+            | ApiError::UnknownAccount(_)
+            | ApiError::UnknownThermostat(_)
+            | ApiError::UnknownPerson(_) => StatusCode::NOT_FOUND,
+            ApiError::Upstream { .. } => StatusCode::BAD_GATEWAY,
+            // Validation and function errors are about the values asked for,
+            // processing errors are temporary, anything else is on castform's
+            // side of the ecobee API.
+            ApiError::Rejected { code: 7, .. } | ApiError::Rejected { code: 11, .. } => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            ApiError::Rejected { code: 3, .. } => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Rejected { .. } => StatusCode::BAD_GATEWAY,
+            ApiError::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::Mailbox | ApiError::Stale(_) | ApiError::CircuitOpen => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+        }
+    }
+
+    fn details(&self) -> Value {
+        match *self {
+            ApiError::Upstream { ref code, .. } => json!({ "ecobee_code": code }),
+            ApiError::Rejected { code, .. } => json!({ "ecobee_code": code }),
+            ApiError::Forbidden { scope, required } => {
+                json!({ "scope": scope, "required": required })
+            }
+            _ => Value::Null,
+        }
+    }
+}
+
+impl From<Error> for ApiError {
+    /// Errors that are not already typed come from talking to ecobee, such
+    /// as connection failures or unparseable responses.
+    fn from(error: Error) -> Self {
+        match error.downcast::<ApiError>() {
+            Ok(error) => error,
+            Err(error) => ApiError::Upstream {
+                code: None,
+                message: error.to_string(),
+            },
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status()).json(json!({
+            "code": self.code(),
+            "message": self.to_string(),
+            "details": self.details(),
+        }))
+    }
+}
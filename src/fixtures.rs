@@ -0,0 +1,443 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use failure::Error;
+use http::{Request, StatusCode};
+use serde_json::{self, Value};
+use serde_urlencoded;
+
+use logging::REDACTED;
+use Result;
+
+/// Keys whose values never end up in a fixture, in query strings and at the
+/// top level of the bodies sent to and received from `AUTH_PATHS`.
+const SECRET_KEYS: &[&str] = &[
+    "access_token",
+    "client_id",
+    "code",
+    "ecobeePin",
+    "password",
+    "refresh_token",
+    "username",
+];
+
+/// Endpoints exchanging credentials and tokens. Other bodies are recorded as
+/// they are, they carry no secrets and replaying needs them intact, e.g. the
+/// `status.code` of every API response.
+const AUTH_PATHS: &[&str] = &["/authorize", "/token"];
+
+/// Where `--record` writes ecobee traffic to or `--replay` serves it from.
+/// Each account uses a subdirectory named after it.
+#[derive(Clone, Debug)]
+pub enum FixtureMode {
+    Record(PathBuf),
+    Replay(PathBuf),
+}
+
+/// Captured ecobee exchanges of one account, stored one JSON file each.
+pub enum Fixtures {
+    Record {
+        dir: PathBuf,
+        sequence: AtomicUsize,
+    },
+    /// Recorded responses by request, in the order they were recorded.
+    Replay {
+        responses: Mutex<HashMap<String, VecDeque<RecordedResponse>>>,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+struct Exchange {
+    request: RecordedRequest,
+    response: RecordedResponse,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RecordedRequest {
+    method: String,
+    path: String,
+    query: Vec<(String, String)>,
+    body: Option<Value>,
+}
+
+impl RecordedRequest {
+    /// Copies the parts of `request` that identify it, with secrets scrubbed.
+    /// Headers are left out since they only carry the token and client
+    /// identification.
+    fn new(request: &Request<Vec<u8>>) -> Result<Self> {
+        let mut query: Vec<(String, String)> =
+            serde_urlencoded::from_str(request.uri().query().unwrap_or(""))?;
+        for &mut (ref key, ref mut value) in &mut query {
+            if SECRET_KEYS.contains(&key.as_str()) {
+                *value = REDACTED.to_owned();
+            }
+        }
+
+        let body = if request.body().is_empty() {
+            None
+        } else {
+            let mut body = serde_json::from_slice(request.body())
+                .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(request.body()).into()));
+            if is_auth_path(request.uri().path()) {
+                scrub(&mut body);
+            }
+            Some(body)
+        };
+
+        Ok(RecordedRequest {
+            method: request.method().to_string(),
+            path: request.uri().path().to_owned(),
+            query,
+            body,
+        })
+    }
+
+    /// Identifies a request for replay. Scrubbing happens first, so the
+    /// credentials used while replaying don't matter.
+    fn key(&self) -> String {
+        format!(
+            "{} {} {} {}",
+            self.method,
+            self.path,
+            serde_json::to_string(&self.query).expect("serialized json"),
+            self.body.as_ref().map(Value::to_string).unwrap_or_default()
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RecordedResponse {
+    status: u16,
+    /// The body, if ecobee answered with JSON.
+    #[serde(default)]
+    body: Option<Value>,
+    /// The body otherwise, e.g. an HTML error page from a proxy.
+    #[serde(default)]
+    text: Option<String>,
+}
+
+impl Fixtures {
+    pub fn open(mode: &FixtureMode, account: &str) -> Result<Fixtures> {
+        match *mode {
+            FixtureMode::Record(ref dir) => Fixtures::record(dir.join(account)),
+            FixtureMode::Replay(ref dir) => Fixtures::replay(&dir.join(account)),
+        }
+    }
+
+    fn record(dir: PathBuf) -> Result<Fixtures> {
+        fs::create_dir_all(&dir).map_err(|e| format_err!("{}: {}", dir.display(), e))?;
+        // Keep numbering after fixtures from earlier runs instead of
+        // overwriting them.
+        let existing = fixture_files(&dir)?.len();
+        info!("recording ecobee traffic to {}", dir.display());
+
+        Ok(Fixtures::Record {
+            dir,
+            sequence: AtomicUsize::new(existing),
+        })
+    }
+
+    fn replay(dir: &Path) -> Result<Fixtures> {
+        let mut responses = HashMap::new();
+        let files = fixture_files(dir)?;
+
+        for path in &files {
+            let mut contents = String::new();
+            File::open(path)
+                .and_then(|mut file| file.read_to_string(&mut contents))
+                .map_err(|e| format_err!("{}: {}", path.display(), e))?;
+            let exchange: Exchange = serde_json::from_str(&contents)
+                .map_err(|e| format_err!("{}: {}", path.display(), e))?;
+
+            responses
+                .entry(exchange.request.key())
+                .or_insert_with(VecDeque::new)
+                .push_back(exchange.response);
+        }
+        info!(
+            "replaying {} ecobee exchanges from {}",
+            files.len(),
+            dir.display()
+        );
+
+        Ok(Fixtures::Replay {
+            responses: Mutex::new(responses),
+        })
+    }
+
+    /// The recorded answer to `request` when replaying, `None` when
+    /// recording. Repeated requests get the recorded responses in order, and
+    /// the last one once they run out, so polling can go on indefinitely.
+    pub fn respond(&self, request: &Request<Vec<u8>>) -> Option<Result<(StatusCode, Vec<u8>)>> {
+        let responses = match *self {
+            Fixtures::Record { .. } => return None,
+            Fixtures::Replay { ref responses } => responses,
+        };
+
+        Some(RecordedRequest::new(request).and_then(|recorded| {
+            let mut responses = responses.lock().expect("fixtures lock");
+            let queue = responses.get_mut(&recorded.key()).ok_or_else(|| {
+                format_err!(
+                    "no recorded response for {} {}",
+                    recorded.method,
+                    recorded.path
+                )
+            })?;
+            let response = if queue.len() > 1 {
+                queue.pop_front().expect("non-empty queue")
+            } else {
+                queue[0].clone()
+            };
+
+            let body = match (response.body, response.text) {
+                (Some(body), _) => body.to_string().into_bytes(),
+                (None, Some(text)) => text.into_bytes(),
+                (None, None) => Vec::new(),
+            };
+
+            Ok((StatusCode::from_u16(response.status)?, body))
+        }))
+    }
+
+    /// Writes an exchange to the next fixture file when recording. Failures
+    /// are logged, they never fail the request itself.
+    pub fn save(&self, request: &Request<Vec<u8>>, status: StatusCode, data: &[u8]) {
+        let (dir, sequence) = match *self {
+            Fixtures::Record {
+                ref dir,
+                ref sequence,
+            } => (dir, sequence),
+            Fixtures::Replay { .. } => return,
+        };
+
+        let request = match RecordedRequest::new(request) {
+            Ok(request) => request,
+            Err(e) => {
+                warn!("failed to record the request to {}: {}", dir.display(), e);
+                return;
+            }
+        };
+        let response = match serde_json::from_slice::<Value>(data) {
+            Ok(mut body) => {
+                if is_auth_path(&request.path) {
+                    scrub(&mut body);
+                }
+                RecordedResponse {
+                    status: status.as_u16(),
+                    body: Some(body),
+                    text: None,
+                }
+            }
+            Err(_) => RecordedResponse {
+                status: status.as_u16(),
+                body: None,
+                text: Some(String::from_utf8_lossy(data).into()),
+            },
+        };
+
+        let number = sequence.fetch_add(1, Ordering::SeqCst) + 1;
+        let name = format!(
+            "{:04}-{}-{}.json",
+            number,
+            request.method,
+            request.path.trim_matches('/').replace('/', "_")
+        );
+        let path = dir.join(name);
+        let exchange = Exchange { request, response };
+        let result = serde_json::to_string_pretty(&exchange)
+            .map_err(Error::from)
+            .and_then(|json| fs::write(&path, json).map_err(Error::from));
+
+        match result {
+            Ok(()) => debug!("recorded {}", path.display()),
+            Err(e) => warn!("failed to record {}: {}", path.display(), e),
+        }
+    }
+}
+
+/// The fixture files in `dir`, in recording order.
+fn fixture_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = fs::read_dir(dir)
+        .map_err(|e| format_err!("{}: {}", dir.display(), e))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "json")
+        })
+        .collect::<Vec<_>>();
+    files.sort();
+
+    Ok(files)
+}
+
+fn is_auth_path(path: &str) -> bool {
+    AUTH_PATHS.contains(&path)
+}
+
+/// Replaces the values of `SECRET_KEYS` at the top level of `value`, either
+/// an object or a list of `[key, value]` pairs like the ones the
+/// authorization request sends. Nested values are left alone.
+fn scrub(value: &mut Value) {
+    match *value {
+        Value::Object(ref mut map) => {
+            for (key, value) in map.iter_mut() {
+                if SECRET_KEYS.contains(&key.as_str()) {
+                    *value = Value::String(REDACTED.to_owned());
+                }
+            }
+        }
+        Value::Array(ref mut items) => {
+            for item in items.iter_mut() {
+                if let Value::Array(ref mut pair) = *item {
+                    let secret = pair.len() == 2
+                        && pair[0]
+                            .as_str()
+                            .is_some_and(|key| SECRET_KEYS.contains(&key));
+
+                    if secret {
+                        pair[1] = Value::String(REDACTED.to_owned());
+                    }
+                }
+            }
+        }
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn request(method: &str, uri: &str, body: &str) -> Request<Vec<u8>> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(body.as_bytes().to_vec())
+            .expect("request")
+    }
+
+    #[test]
+    fn scrub_replaces_top_level_secrets() {
+        let mut value = json!({
+            "access_token": "abc",
+            "scope": "smartWrite",
+            "status": {"code": 0, "message": ""},
+        });
+        scrub(&mut value);
+        assert_eq!(
+            value,
+            json!({
+                "access_token": REDACTED,
+                "scope": "smartWrite",
+                "status": {"code": 0, "message": ""},
+            })
+        );
+
+        let mut pairs = json!([["grant_type", "password"], ["password", "hunter2"]]);
+        scrub(&mut pairs);
+        assert_eq!(
+            pairs,
+            json!([["grant_type", "password"], ["password", REDACTED]])
+        );
+    }
+
+    #[test]
+    fn only_auth_bodies_are_scrubbed() {
+        let write = RecordedRequest::new(&request(
+            "POST",
+            "https://api.ecobee.com/1/thermostat?format=json",
+            r#"{"functions":[{"type":"setHold","params":{"code":"x"}}]}"#,
+        ))
+        .unwrap();
+        assert_eq!(write.body.unwrap()["functions"][0]["params"]["code"], "x");
+
+        let auth = RecordedRequest::new(&request(
+            "POST",
+            "https://api.ecobee.com/authorize?client_id=secret&response_type=ecobeePin",
+            "",
+        ))
+        .unwrap();
+        assert_eq!(
+            auth.query,
+            vec![
+                ("client_id".to_owned(), REDACTED.to_owned()),
+                ("response_type".to_owned(), "ecobeePin".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn key_ignores_credentials() {
+        let key = |uri: &str, body: &str| {
+            RecordedRequest::new(&request("POST", uri, body))
+                .unwrap()
+                .key()
+        };
+
+        assert_eq!(
+            key(
+                "https://api.ecobee.com/token?code=one",
+                r#"{"refresh_token":"one"}"#
+            ),
+            key(
+                "https://api.ecobee.com/token?code=two",
+                r#"{"refresh_token":"two"}"#
+            )
+        );
+        assert_ne!(
+            key(
+                "https://api.ecobee.com/1/thermostat",
+                r#"{"fanMinOnTime":5}"#
+            ),
+            key(
+                "https://api.ecobee.com/1/thermostat",
+                r#"{"fanMinOnTime":10}"#
+            )
+        );
+        assert_ne!(
+            key("https://api.ecobee.com/1/thermostat?format=json", ""),
+            key("https://api.ecobee.com/1/thermostat?format=xml", "")
+        );
+        assert_ne!(
+            key("https://api.ecobee.com/1/thermostat", ""),
+            key("https://api.ecobee.com/1/thermostatSummary", "")
+        );
+    }
+
+    #[test]
+    fn replays_what_was_recorded() {
+        let dir = env::temp_dir().join(format!("castform-fixtures-{}", ::std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let get = request("GET", "https://api.ecobee.com/1/thermostat?json=%7B%7D", "");
+
+        let recorder = Fixtures::open(&FixtureMode::Record(dir.clone()), "home").unwrap();
+        assert!(recorder.respond(&get).is_none());
+        recorder.save(&get, StatusCode::OK, br#"{"status":{"code":0}}"#);
+        recorder.save(&get, StatusCode::INTERNAL_SERVER_ERROR, b"<html>");
+
+        let replayer = Fixtures::open(&FixtureMode::Replay(dir.clone()), "home").unwrap();
+        let answer = || replayer.respond(&get).unwrap().unwrap();
+        assert_eq!(
+            answer(),
+            (StatusCode::OK, br#"{"status":{"code":0}}"#.to_vec())
+        );
+        assert_eq!(
+            answer(),
+            (StatusCode::INTERNAL_SERVER_ERROR, b"<html>".to_vec())
+        );
+        assert_eq!(
+            answer(),
+            (StatusCode::INTERNAL_SERVER_ERROR, b"<html>".to_vec())
+        );
+
+        let other = request("GET", "https://api.ecobee.com/1/thermostatSummary", "");
+        assert!(replayer.respond(&other).unwrap().is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
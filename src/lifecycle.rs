@@ -0,0 +1,206 @@
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use actix::actors::signal::{ProcessSignals, Signal, SignalType, Subscribe};
+use actix::{
+    Actor, ActorFuture, AsyncContext, Context, Handler, Recipient, SpawnHandle, System,
+    SystemService, WrapFuture,
+};
+use actix_web::server::StopServer;
+use failure::{err_msg, Error};
+use futures::{future, Future};
+use tokio::timer::Deadline;
+
+use backend::Drain;
+use query::HealthQuery;
+use server::Account;
+use systemd::{self, Notifier};
+
+/// Exit status when in-flight work did not finish cleanly within the
+/// shutdown timeout.
+const EXIT_FAILURE: i32 = 1;
+/// Exit status when a second signal interrupted the graceful shutdown.
+const EXIT_INTERRUPTED: i32 = 130;
+
+/// Supervises the process: shuts down gracefully on SIGINT, SIGTERM or
+/// SIGQUIT, and keeps systemd informed when started with `Type=notify`.
+///
+/// On the first signal the HTTP servers stop accepting connections and
+/// finish the requests in progress, then every account sends its coalesced
+/// writes, saves its ecobee token to `state_dir` and waits for the writes in
+/// flight, all within `shutdown_timeout`.
+/// A second signal exits right away.
+pub struct Lifecycle {
+    accounts: Vec<Account>,
+    servers: Vec<Recipient<StopServer>>,
+    shutdown_timeout: Duration,
+    notifier: Option<Rc<Notifier>>,
+    ready_check: Option<SpawnHandle>,
+    stopping: bool,
+    exit_code: Rc<Cell<i32>>,
+}
+
+impl Lifecycle {
+    /// `exit_code` receives the status the process should exit with once the
+    /// system has stopped.
+    pub fn new(
+        accounts: Vec<Account>,
+        servers: Vec<Recipient<StopServer>>,
+        shutdown_timeout: Duration,
+        exit_code: Rc<Cell<i32>>,
+    ) -> Self {
+        Lifecycle {
+            accounts,
+            servers,
+            shutdown_timeout,
+            notifier: Notifier::from_env().map(Rc::new),
+            ready_check: None,
+            stopping: false,
+            exit_code,
+        }
+    }
+
+    fn notify(&self, state: &str) {
+        if let Some(ref notifier) = self.notifier {
+            notifier.notify(state);
+        }
+    }
+
+    /// Resolves with whether every account answers and has polled ecobee
+    /// successfully at least once.
+    fn all_polled(&self) -> impl Future<Item = bool, Error = Error> {
+        let checks = self
+            .accounts
+            .iter()
+            .map(|account| {
+                account
+                    .backend
+                    .send(HealthQuery)
+                    .map_err(|_| err_msg("mailbox error"))
+                    .map(|health| health.last_poll.is_some())
+            })
+            .collect::<Vec<_>>();
+
+        future::join_all(checks).map(|polled| polled.into_iter().all(|polled| polled))
+    }
+
+    fn shutdown(&mut self, ctx: &mut Context<Self>) {
+        self.stopping = true;
+        self.notify("STOPPING=1");
+        info!(
+            "shutting down, waiting up to {}s for requests and ecobee writes in flight",
+            self.shutdown_timeout.as_secs()
+        );
+
+        let stop_servers = future::join_all(
+            self.servers
+                .iter()
+                .map(|server| server.send(StopServer { graceful: true }).then(|_| Ok(())))
+                .collect::<Vec<_>>(),
+        );
+        let drains = self
+            .accounts
+            .iter()
+            .map(|account| {
+                account
+                    .backend
+                    .send(Drain)
+                    .map_err(|_| err_msg("mailbox error"))
+                    .and_then(|resp| resp)
+                    .and_then(|fut| fut)
+            })
+            .collect::<Vec<_>>();
+
+        let shutdown = stop_servers.and_then(|_| future::join_all(drains));
+        let deadline = Instant::now() + self.shutdown_timeout;
+
+        ctx.spawn(Deadline::new(shutdown, deadline).into_actor(self).then(
+            |result, lifecycle, _| {
+                match result {
+                    Ok(_) => info!("shut down cleanly"),
+                    Err(ref e) if e.is_elapsed() => {
+                        error!("shutdown timed out with ecobee writes still in flight");
+                        lifecycle.exit_code.set(EXIT_FAILURE);
+                    }
+                    Err(e) => {
+                        if let Some(e) = e.into_inner() {
+                            error!("failed to shut down cleanly: {}", e);
+                        }
+                        lifecycle.exit_code.set(EXIT_FAILURE);
+                    }
+                }
+
+                System::current().stop();
+                ::actix::fut::ok(())
+            },
+        ));
+    }
+}
+
+impl Actor for Lifecycle {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ProcessSignals::from_registry().do_send(Subscribe(ctx.address().recipient()));
+
+        if self.notifier.is_none() {
+            return;
+        }
+
+        // systemd considers the bridge started once it is actually useful,
+        // which is after the first successful poll of every account.
+        self.ready_check = Some(ctx.run_interval(Duration::from_secs(1), |lifecycle, ctx| {
+            ctx.spawn(lifecycle.all_polled().into_actor(lifecycle).then(
+                |polled, lifecycle, ctx| {
+                    if let Ok(true) = polled {
+                        if let Some(handle) = lifecycle.ready_check.take() {
+                            ctx.cancel_future(handle);
+                            lifecycle.notify("READY=1");
+                            info!("notified systemd that castform is ready");
+                        }
+                    }
+
+                    ::actix::fut::ok(())
+                },
+            ));
+        }));
+
+        if let Some(interval) = systemd::watchdog_interval() {
+            // Only ping while the actors still answer, so systemd restarts a
+            // wedged bridge.
+            ctx.run_interval(interval, |lifecycle, ctx| {
+                let notifier = lifecycle.notifier.clone();
+
+                ctx.spawn(lifecycle.all_polled().into_actor(lifecycle).then(
+                    move |answered, _, _| {
+                        if let (Ok(_), Some(notifier)) = (answered, notifier) {
+                            notifier.notify("WATCHDOG=1");
+                        }
+
+                        ::actix::fut::ok(())
+                    },
+                ));
+            });
+        }
+    }
+}
+
+impl Handler<Signal> for Lifecycle {
+    type Result = ();
+
+    fn handle(&mut self, msg: Signal, ctx: &mut Context<Self>) {
+        match msg.0 {
+            SignalType::Int | SignalType::Term | SignalType::Quit if self.stopping => {
+                warn!("{:?} received during shutdown, exiting now", msg.0);
+                self.exit_code.set(EXIT_INTERRUPTED);
+                System::current().stop();
+            }
+            SignalType::Int | SignalType::Term | SignalType::Quit => {
+                info!("{:?} received", msg.0);
+                self.shutdown(ctx);
+            }
+            _ => (),
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use std::io::Write;
+use std::sync::{Arc, RwLock};
+
+use env_logger::{Builder, Logger};
+use log::{self, LevelFilter, Log, Metadata, Record};
+
+/// Placeholder printed instead of secrets such as tokens and passwords.
+pub const REDACTED: &str = "[redacted]";
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// Handle to the installed logger, used to change the level on config reload.
+#[derive(Clone)]
+pub struct LogHandle(Arc<ReloadableLogger>);
+
+struct ReloadableLogger {
+    format: LogFormat,
+    filters: Option<String>,
+    logger: RwLock<Logger>,
+}
+
+/// Installs the global logger. `RUST_LOG` style filters in `filters` take
+/// precedence over `level` for the modules they name.
+pub fn init(level: LevelFilter, format: LogFormat, filters: Option<&str>) -> LogHandle {
+    let logger = build(level, format, filters);
+    log::set_max_level(logger.filter());
+
+    let handle = LogHandle(Arc::new(ReloadableLogger {
+        format,
+        filters: filters.map(str::to_owned),
+        logger: RwLock::new(logger),
+    }));
+    log::set_boxed_logger(Box::new(handle.clone())).expect("logger already installed");
+
+    handle
+}
+
+impl LogHandle {
+    pub fn set_level(&self, level: LevelFilter) {
+        let inner = &self.0;
+        let logger = build(level, inner.format, inner.filters.as_deref());
+        let filter = logger.filter();
+
+        *inner.logger.write().expect("logger lock") = logger;
+        log::set_max_level(filter);
+    }
+}
+
+impl Log for LogHandle {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.0.logger.read().expect("logger lock").enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.0.logger.read().expect("logger lock").log(record)
+    }
+
+    fn flush(&self) {
+        self.0.logger.read().expect("logger lock").flush()
+    }
+}
+
+fn build(level: LevelFilter, format: LogFormat, filters: Option<&str>) -> Logger {
+    let mut builder = Builder::new();
+    builder.filter_level(level);
+
+    if let Some(filters) = filters {
+        builder.parse(filters);
+    }
+
+    if format == LogFormat::Json {
+        builder.format(|buf, record| {
+            let line = json!({
+                "timestamp": buf.precise_timestamp().to_string(),
+                "level": record.level().to_string(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+
+            writeln!(buf, "{}", line)
+        });
+    }
+
+    builder.build()
+}
@@ -1,14 +1,21 @@
 extern crate actix;
-#[macro_use]
 extern crate actix_derive;
 extern crate actix_web;
+extern crate base64;
+extern crate chrono;
+extern crate chrono_tz;
 extern crate clap;
+extern crate env_logger;
 #[macro_use]
 extern crate failure;
 extern crate futures;
 extern crate http;
 extern crate hyper;
 extern crate hyper_tls;
+#[macro_use]
+extern crate log;
+extern crate openssl;
+extern crate rand;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
@@ -18,22 +25,55 @@ extern crate serde_urlencoded;
 extern crate tokio;
 extern crate toml;
 
+mod auth;
+mod backend;
+mod cli;
 mod config;
 mod ecobee;
+mod error;
+mod fixtures;
+mod lifecycle;
+mod logging;
+mod presence;
 mod query;
+mod ratelimit;
+mod reconcile;
+mod reload;
+mod request_id;
 mod response;
+mod retry;
+mod rules;
+mod schedules;
 mod server;
+mod simulator;
+mod state;
+mod systemd;
+mod temperature;
+mod thermostat;
+mod tls;
 
-use std::fs::File;
-use std::io::Read;
+use std::cell::Cell;
+use std::cmp;
+use std::env;
+use std::process;
+use std::rc::Rc;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
 use actix::Actor;
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, SubCommand};
 use failure::{err_msg, Error};
+use log::LevelFilter;
 
-use ecobee::EcobeeActor;
+use config::Config;
+use fixtures::FixtureMode;
+use lifecycle::Lifecycle;
+use logging::LogFormat;
+use presence::Household;
+use reload::ConfigWatcher;
+use server::{Account, ServerSettings};
 
-const VERSION: &'static str = "0.0.1";
+const VERSION: &str = "0.0.1";
 
 pub type Result<R> = std::result::Result<R, Error>;
 
@@ -65,36 +105,212 @@ fn build_clap<'a, 'b>() -> App<'a, 'b> {
                 .default_value("8351")
                 .help("HTTP port to listen to"),
         )
+        .arg(
+            Arg::with_name("tls-cert")
+                .long("tls-cert")
+                .value_name("CERT_FILE")
+                .requires("tls-key")
+                .help("PEM certificate chain to serve HTTPS with"),
+        )
+        .arg(
+            Arg::with_name("tls-key")
+                .long("tls-key")
+                .value_name("KEY_FILE")
+                .requires("tls-cert")
+                .help("PEM private key for the TLS certificate"),
+        )
+        .arg(
+            Arg::with_name("tls-client-ca")
+                .long("tls-client-ca")
+                .value_name("CA_FILE")
+                .help("require client certificates signed by this CA"),
+        )
+        .arg(
+            Arg::with_name("log-level")
+                .long("log-level")
+                .value_name("LEVEL")
+                .possible_values(&["off", "error", "warn", "info", "debug", "trace"])
+                .help("minimum level of log messages to print [default: info]"),
+        )
+        .arg(
+            Arg::with_name("log-format")
+                .long("log-format")
+                .value_name("FORMAT")
+                .possible_values(&["text", "json"])
+                .help("print log messages as plain text or JSON lines"),
+        )
+        .arg(
+            Arg::with_name("record")
+                .long("record")
+                .value_name("DIR")
+                .conflicts_with("replay")
+                .help("save every ecobee request and response, with secrets scrubbed, to DIR"),
+        )
+        .arg(
+            Arg::with_name("replay")
+                .long("replay")
+                .value_name("DIR")
+                .help("answer ecobee requests from the fixtures in DIR instead of the network"),
+        )
+        .arg(
+            Arg::with_name("watch-config")
+                .long("watch-config")
+                .help("reload the config file when it changes, not only on SIGHUP"),
+        )
+        .subcommand(
+            SubCommand::with_name("config")
+                .about("inspect the configuration")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("check")
+                        .about("validate the config and print it with secrets redacted"),
+                ),
+        )
+        .subcommands(cli::subcommands())
 }
 
-fn main() -> Result<()> {
-    let system = actix::System::new("castform");
+fn check_config(config: &Config) -> Result<()> {
+    print!("{}", config.redacted().to_toml()?);
+    config.validate()?;
+    eprintln!("configuration is valid");
 
+    Ok(())
+}
+
+fn run() -> Result<()> {
     let matches = build_clap().get_matches();
 
-    let config = matches
+    let config_path = matches
         .value_of("config")
         .ok_or_else(|| err_msg("must provide config"))?;
-    let mut config = File::open(config)?;
-    let mut contents = String::new();
-    config.read_to_string(&mut contents)?;
+    let cli_log_level = match matches.value_of("log-level") {
+        Some(level) => Some(
+            level
+                .parse::<LevelFilter>()
+                .map_err(|_| err_msg("invalid log level"))?,
+        ),
+        None => None,
+    };
+
+    if let Some((command, args)) = cli::Command::from_matches(&matches)? {
+        logging::init(
+            cli_log_level.unwrap_or(LevelFilter::Warn),
+            LogFormat::Text,
+            env::var("RUST_LOG").ok().as_deref(),
+        );
+        return command.run(args, config_path);
+    }
 
-    let config = toml::from_str(&contents)?;
+    let config = Config::load(config_path)?;
 
-    let ecobee =
-        EcobeeActor::from_config(&config).map(|actor| EcobeeActor::create(move |_| actor))?;
-    let server = actix_web::server::new(move || server::build_server_factory(ecobee.clone()));
+    if let ("config", Some(_)) = matches.subcommand() {
+        return check_config(&config);
+    }
+    config.validate()?;
+
+    let system = actix::System::new("castform");
+
+    let log_level = cli_log_level
+        .or_else(|| {
+            config
+                .log_level
+                .as_ref()
+                .and_then(|level| level.parse().ok())
+        })
+        .unwrap_or(LevelFilter::Info);
+    let log_format = match matches.value_of("log-format") {
+        Some("json") => LogFormat::Json,
+        Some(_) => LogFormat::Text,
+        None => config.log_format,
+    };
+    let log = logging::init(log_level, log_format, env::var("RUST_LOG").ok().as_deref());
+
+    let fixtures = match (matches.value_of("record"), matches.value_of("replay")) {
+        (Some(dir), _) => Some(FixtureMode::Record(dir.into())),
+        (_, Some(dir)) => Some(FixtureMode::Replay(dir.into())),
+        (None, None) => None,
+    };
+    let accounts = config
+        .accounts()
+        .iter()
+        .map(|account| {
+            let backend = backend::start(&config, account, fixtures.as_ref())?;
+            let presence = Household::new(&config, &account.name, &backend).start();
+
+            Ok(Account {
+                name: account.name.clone(),
+                backend,
+                presence,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let settings = Arc::new(RwLock::new(ServerSettings::from_config(&config)));
+    ConfigWatcher::new(
+        config_path,
+        config.clone(),
+        accounts.clone(),
+        settings.clone(),
+        log,
+        cli_log_level,
+        matches.is_present("watch-config"),
+    )
+    .start();
+
+    let server_accounts = accounts.clone();
+    let server = actix_web::server::new(move || {
+        server::build_server_factory(server_accounts.clone(), settings.clone())
+    })
+    .disable_signals()
+    .shutdown_timeout(cmp::min(config.shutdown_timeout, u64::from(u16::MAX)) as u16);
 
     let host = matches.value_of("host").unwrap();
     let port = matches.value_of("port").unwrap();
     let addr = format!("{}:{}", host, port);
 
-    let server = server.bind(addr.clone())?;
-    server.start();
+    let tls_cert = matches.value_of("tls-cert").or(config.tls_cert.as_deref());
+    let tls_key = matches.value_of("tls-key").or(config.tls_key.as_deref());
+    let tls_client_ca = matches
+        .value_of("tls-client-ca")
+        .or(config.tls_client_ca.as_deref());
 
-    println!("Starting HTTP server: http://{}", addr);
+    let server = match (tls_cert, tls_key) {
+        (Some(cert), Some(key)) => {
+            let acceptor = tls::build_acceptor(cert, key, tls_client_ca)?;
+            let server = server.bind_ssl(addr.clone(), acceptor)?.start();
+            info!("Starting HTTP server: https://{}", addr);
+            server
+        }
+        (None, None) => {
+            if tls_client_ca.is_some() {
+                return Err(err_msg("tls_client_ca requires tls_cert and tls_key"));
+            }
+            let server = server.bind(addr.clone())?.start();
+            info!("Starting HTTP server: http://{}", addr);
+            server
+        }
+        _ => return Err(err_msg("tls_cert and tls_key must be set together")),
+    };
+
+    let exit_code = Rc::new(Cell::new(0));
+    Lifecycle::new(
+        accounts,
+        vec![server.recipient()],
+        Duration::from_secs(config.shutdown_timeout),
+        exit_code.clone(),
+    )
+    .start();
 
     let _ = system.run();
 
-    Ok(())
+    match exit_code.get() {
+        0 => Ok(()),
+        code => process::exit(code),
+    }
+}
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("castform: {}", e);
+        process::exit(1);
+    }
 }
@@ -0,0 +1,772 @@
+//! Household occupancy reported through `/presence/{person}`, e.g. by phone
+//! geofences or a router script. Once the last person has left and the house
+//! stayed empty for the grace period, the account's thermostats are held at
+//! the away comfort setting; the first person arriving resumes their program.
+//! Who is home and whether castform set the away hold is kept in
+//! `state_dir`, so a restart while everyone is out still resumes on return.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use actix::{
+    Actor, ActorFuture, AsyncContext, Context, Handler, Message, MessageResult, Recipient,
+    SpawnHandle, WrapFuture,
+};
+use failure::{err_msg, Error};
+use futures::{stream, Future, Stream};
+
+use backend::{BackendAddr, ChangeThermostat, HoldType, Reconfigure, TargetedChange};
+use config::{is_valid_name, Config};
+use error::ApiError;
+use query::ThermostatsQuery;
+use request_id::{RequestId, Traced};
+use response::{PersonReport, PresenceReport};
+use state::{load_json_state, save_json_state, unix_time};
+use Result;
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct PresenceConfig {
+    /// Seconds the house has to stay empty before the away hold is set, so a
+    /// phone briefly dropping off the network changes nothing.
+    pub grace_period: u64,
+    /// Comfort setting held while nobody is home.
+    pub away_climate: String,
+    /// The people allowed to report, anyone when empty.
+    pub people: Vec<String>,
+}
+
+impl Default for PresenceConfig {
+    fn default() -> Self {
+        PresenceConfig {
+            grace_period: 600,
+            away_climate: "away".to_owned(),
+            people: Vec::new(),
+        }
+    }
+}
+
+/// Problems with `config`. Whether the thermostat knows `away_climate` is
+/// only checked when the hold is set.
+pub fn validate(config: &PresenceConfig, problems: &mut Vec<String>) {
+    if config.away_climate.is_empty() {
+        problems.push("presence.away_climate must not be empty".to_owned());
+    }
+
+    let mut names = Vec::new();
+    for person in &config.people {
+        if !is_valid_name(person) {
+            problems.push(format!(
+                "presence.people entry `{}` must be non-empty and only use letters, digits, `-` and `_`",
+                person
+            ));
+        } else if names.contains(&person) {
+            problems.push(format!(
+                "presence.people entry `{}` is listed twice",
+                person
+            ));
+        }
+        names.push(person);
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum PresenceEvent {
+    Arrive,
+    Leave,
+}
+
+/// A person arriving or leaving, sent as `Traced<ReportPresence>` so the
+/// writes it causes carry the HTTP request's ID. Answered with the
+/// household's presence after the report.
+pub struct ReportPresence {
+    pub person: String,
+    pub event: PresenceEvent,
+}
+
+impl Message for ReportPresence {
+    type Result = Result<PresenceReport>;
+}
+
+/// Asks for the household's presence.
+pub struct PresenceQuery;
+
+impl Message for PresenceQuery {
+    type Result = PresenceReport;
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+struct Person {
+    home: bool,
+    /// Unix time of the report that changed `home`.
+    since: u64,
+}
+
+/// What is kept across restarts.
+#[derive(Deserialize, Serialize, Default)]
+struct State {
+    people: BTreeMap<String, Person>,
+    /// Whether castform set the away hold and has to resume the program once
+    /// someone arrives.
+    away_hold: bool,
+}
+
+/// How a write went on each thermostat, by identifier.
+type Outcomes = BTreeMap<String, Result<()>>;
+
+/// Tracks who of an account's household is home and sends the away hold and
+/// program resumes to each of the account's thermostats.
+pub struct Household {
+    account: String,
+    /// The account's backend, split by message so tests can stand in for it.
+    thermostats: Recipient<ThermostatsQuery>,
+    changes: Recipient<Traced<TargetedChange>>,
+    config: PresenceConfig,
+    state: State,
+    state_path: Option<PathBuf>,
+    /// The timer setting the away hold, and when it fires, while the grace
+    /// period runs.
+    departure: Option<(SpawnHandle, u64)>,
+    /// Whether an away hold or resume is being written. Arrivals and
+    /// departures meanwhile are settled once it completes, so a resume can't
+    /// overtake the hold it is meant to cancel.
+    writing: bool,
+    last_result: Option<String>,
+    last_results: BTreeMap<String, String>,
+}
+
+impl Household {
+    pub fn new(config: &Config, account: &str, backend: &BackendAddr) -> Self {
+        Household::with_recipients(config, account, backend.recipient(), backend.recipient())
+    }
+
+    fn with_recipients(
+        config: &Config,
+        account: &str,
+        thermostats: Recipient<ThermostatsQuery>,
+        changes: Recipient<Traced<TargetedChange>>,
+    ) -> Self {
+        let state_path = config
+            .state_dir
+            .as_ref()
+            .map(|dir| PathBuf::from(dir).join(format!("{}-presence.json", account)));
+        let state = state_path
+            .as_ref()
+            .map(|path| load_json_state(path, "presence state"))
+            .unwrap_or_default();
+
+        Household {
+            account: account.to_owned(),
+            thermostats,
+            changes,
+            config: config.presence.clone(),
+            state,
+            state_path,
+            departure: None,
+            writing: false,
+            last_result: None,
+            last_results: BTreeMap::new(),
+        }
+    }
+
+    /// Whether anyone is home, unknown until someone reported.
+    fn occupied(&self) -> Option<bool> {
+        if self.state.people.is_empty() {
+            None
+        } else {
+            Some(self.state.people.values().any(|person| person.home))
+        }
+    }
+
+    /// Starts or cancels the grace period, or resumes the program, to match
+    /// who is home now.
+    fn settle(&mut self, ctx: &mut Context<Self>, request_id: RequestId) {
+        if self.writing {
+            return;
+        }
+
+        match self.occupied() {
+            Some(true) => {
+                if let Some((handle, _)) = self.departure.take() {
+                    ctx.cancel_future(handle);
+                    info!(
+                        "[{}] someone is home at {} again, not setting the away hold",
+                        request_id, self.account
+                    );
+                }
+                if self.state.away_hold {
+                    self.resume(ctx, request_id);
+                }
+            }
+            Some(false) if self.departure.is_none() && !self.state.away_hold => {
+                let grace_period = self.config.grace_period;
+                info!(
+                    "[{}] nobody is home at {}, setting the away hold in {}s",
+                    request_id, self.account, grace_period
+                );
+
+                let handle = ctx.run_later(Duration::from_secs(grace_period), |household, ctx| {
+                    household.departure = None;
+                    household.hold_away(ctx);
+                });
+                self.departure = Some((handle, unix_time(SystemTime::now()) + grace_period));
+            }
+            _ => {}
+        }
+    }
+
+    fn hold_away(&mut self, ctx: &mut Context<Self>) {
+        let request_id = RequestId::generate();
+        let climate = self.config.away_climate.clone();
+        info!(
+            "[{}] holding {} at `{}` while nobody is home",
+            request_id, self.account, climate
+        );
+
+        // Set before the write completes, so an arrival racing it still
+        // resumes the program once the hold is in place.
+        self.state.away_hold = true;
+        self.save();
+
+        let change = ChangeThermostat::Climate(climate, HoldType::Indefinite);
+        self.writing = true;
+        ctx.spawn(
+            self.write(request_id.clone(), change)
+                .into_actor(self)
+                .then(move |result, household, ctx| {
+                    household.writing = false;
+                    let (held, _) =
+                        household.record(&request_id, "set the away hold", "away hold set", result);
+                    if held == 0 {
+                        household.state.away_hold = false;
+                        household.save();
+                    }
+                    // Resumes right away if someone arrived meanwhile, or
+                    // starts another grace period if the hold failed.
+                    household.settle(ctx, request_id);
+
+                    ::actix::fut::ok(())
+                }),
+        );
+    }
+
+    fn resume(&mut self, ctx: &mut Context<Self>, request_id: RequestId) {
+        info!(
+            "[{}] someone arrived at {}, resuming the program",
+            request_id, self.account
+        );
+
+        self.writing = true;
+        ctx.spawn(
+            self.write(request_id.clone(), ChangeThermostat::ResumeProgram)
+                .into_actor(self)
+                .then(move |result, household, ctx| {
+                    household.writing = false;
+                    let (_, failed) = household.record(
+                        &request_id,
+                        "resume the program",
+                        "program resumed",
+                        result,
+                    );
+                    // Otherwise the away hold stays recorded, so the next
+                    // arrival tries again.
+                    if failed == 0 {
+                        household.state.away_hold = false;
+                        household.save();
+                        // Starts the grace period if everyone left
+                        // meanwhile.
+                        household.settle(ctx, request_id);
+                    }
+
+                    ::actix::fut::ok(())
+                }),
+        );
+    }
+
+    /// Sends `change` to each of the account's thermostats, one after
+    /// another. Fails only if they can't be listed.
+    fn write(
+        &self,
+        request_id: RequestId,
+        change: ChangeThermostat,
+    ) -> impl Future<Item = Outcomes, Error = Error> {
+        let changes = self.changes.clone();
+        self.thermostats
+            .send(ThermostatsQuery)
+            .map_err(|_| err_msg("mailbox error"))
+            .and_then(|resp| resp)
+            .and_then(move |thermostats| {
+                stream::iter_ok(thermostats).fold(Outcomes::new(), move |mut outcomes, summary| {
+                    changes
+                        .send(Traced::new(
+                            request_id.clone(),
+                            TargetedChange {
+                                thermostat: Some(summary.identifier.clone()),
+                                change: change.clone(),
+                            },
+                        ))
+                        .map_err(|_| err_msg("mailbox error"))
+                        .and_then(|resp| resp)
+                        .and_then(|fut| fut)
+                        .then(move |result| {
+                            outcomes.insert(summary.identifier, result.map(|_| ()));
+                            Ok::<_, Error>(outcomes)
+                        })
+                })
+            })
+    }
+
+    /// Logs and reports how `action` went, `done` once it worked, and
+    /// returns on how many thermostats it worked and failed. Not getting to
+    /// any thermostat counts as one failure.
+    fn record(
+        &mut self,
+        request_id: &RequestId,
+        action: &str,
+        done: &str,
+        result: Result<Outcomes>,
+    ) -> (usize, usize) {
+        let outcomes = match result {
+            Ok(outcomes) => outcomes,
+            Err(e) => {
+                warn!("[{}] failed to {}: {}", request_id, action, e);
+                self.last_result = Some(format!("failed to {}: {}", action, e));
+                return (0, 1);
+            }
+        };
+
+        self.last_results.clear();
+        let mut failed = 0;
+        for (identifier, outcome) in outcomes {
+            let result = match outcome {
+                Ok(()) => done.to_owned(),
+                Err(e) => {
+                    warn!(
+                        "[{}] failed to {} on {}: {}",
+                        request_id, action, identifier, e
+                    );
+                    failed += 1;
+                    format!("failed: {}", e)
+                }
+            };
+            self.last_results.insert(identifier, result);
+        }
+
+        let total = self.last_results.len();
+        self.last_result = Some(if failed == 0 {
+            done.to_owned()
+        } else {
+            format!(
+                "failed to {} on {} of {} thermostats",
+                action, failed, total
+            )
+        });
+
+        (total - failed, failed)
+    }
+
+    fn report(&self) -> PresenceReport {
+        PresenceReport {
+            occupied: self.occupied(),
+            people: self
+                .state
+                .people
+                .iter()
+                .map(|(name, person)| {
+                    (
+                        name.clone(),
+                        PersonReport {
+                            home: person.home,
+                            since: person.since,
+                        },
+                    )
+                })
+                .collect(),
+            away_hold: self.state.away_hold,
+            away_hold_at: self.departure.as_ref().map(|&(_, at)| at),
+            grace_period: self.config.grace_period,
+            last_result: self.last_result.clone(),
+            thermostats: self.last_results.clone(),
+        }
+    }
+
+    /// Failures are logged, at worst a restart forgets who is home.
+    fn save(&self) {
+        let path = match self.state_path {
+            Some(ref path) => path,
+            None => return,
+        };
+
+        if let Err(e) = save_json_state(path, &self.state) {
+            warn!(
+                "failed to save the presence of {} to {}: {}",
+                self.account,
+                path.display(),
+                e
+            );
+        }
+    }
+}
+
+impl Actor for Household {
+    type Context = Context<Self>;
+
+    /// Picks the grace period back up if everyone was out when castform
+    /// stopped and the away hold wasn't set yet.
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.settle(ctx, RequestId::generate());
+    }
+}
+
+impl Handler<Traced<ReportPresence>> for Household {
+    type Result = Result<PresenceReport>;
+
+    fn handle(&mut self, traced: Traced<ReportPresence>, ctx: &mut Context<Self>) -> Self::Result {
+        let Traced {
+            request_id,
+            message: ReportPresence { person, event },
+        } = traced;
+        if self.config.people.is_empty() {
+            if !is_valid_name(&person) {
+                return Err(ApiError::Validation(format!(
+                    "person `{}` must only use letters, digits, `-` and `_`",
+                    person
+                ))
+                .into());
+            }
+        } else if !self.config.people.contains(&person) {
+            return Err(ApiError::UnknownPerson(person).into());
+        }
+
+        let home = event == PresenceEvent::Arrive;
+        info!(
+            "[{}] {} {} {}",
+            request_id,
+            person,
+            if home { "arrived at" } else { "left" },
+            self.account
+        );
+
+        if self.state.people.get(&person).map(|known| known.home) != Some(home) {
+            self.state.people.insert(
+                person,
+                Person {
+                    home,
+                    since: unix_time(SystemTime::now()),
+                },
+            );
+            self.save();
+        }
+        self.settle(ctx, request_id);
+
+        Ok(self.report())
+    }
+}
+
+impl Handler<PresenceQuery> for Household {
+    type Result = MessageResult<PresenceQuery>;
+
+    fn handle(&mut self, _: PresenceQuery, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.report())
+    }
+}
+
+impl Handler<Reconfigure> for Household {
+    type Result = ();
+
+    /// A shorter or longer grace period applies from the next departure on.
+    /// People removed from `people` no longer count towards occupancy.
+    fn handle(&mut self, msg: Reconfigure, ctx: &mut Context<Self>) {
+        self.config = msg.0.presence;
+
+        if !self.config.people.is_empty() {
+            let people = &self.config.people;
+            let before = self.state.people.len();
+            self.state.people.retain(|name, _| people.contains(name));
+            if self.state.people.len() != before {
+                self.save();
+                self.settle(ctx, RequestId::generate());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+
+    use actix::{Arbiter, System};
+    use tokio::timer::Delay;
+
+    use response::{ThermostatStatus, ThermostatSummary};
+    use thermostat::Thermostat;
+
+    /// How long the stub takes to write a change.
+    const WRITE_MS: u64 = 50;
+
+    /// Stands in for the backend of an account with two thermostats, logging
+    /// the changes it is sent. The first `failures` writes fail.
+    struct Stub {
+        writes: Arc<Mutex<Vec<String>>>,
+        failures: usize,
+    }
+
+    impl Actor for Stub {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<ThermostatsQuery> for Stub {
+        type Result = Result<Vec<ThermostatSummary>>;
+
+        fn handle(&mut self, _: ThermostatsQuery, _: &mut Context<Self>) -> Self::Result {
+            Ok(["upstairs", "downstairs"]
+                .iter()
+                .map(|name| ThermostatSummary {
+                    identifier: name.to_string(),
+                    name: name.to_string(),
+                    hvac_mode: "heat".to_owned(),
+                })
+                .collect())
+        }
+    }
+
+    impl Handler<Traced<TargetedChange>> for Stub {
+        type Result = <TargetedChange as Message>::Result;
+
+        fn handle(
+            &mut self,
+            traced: Traced<TargetedChange>,
+            _: &mut Context<Self>,
+        ) -> Self::Result {
+            let TargetedChange { thermostat, change } = traced.message;
+            let change = match change {
+                ChangeThermostat::Climate(climate, _) => format!("hold {}", climate),
+                ChangeThermostat::ResumeProgram => "resume".to_owned(),
+                _ => "other".to_owned(),
+            };
+            self.writes.lock().expect("writes").push(format!(
+                "{} {}",
+                thermostat.expect("thermostat"),
+                change
+            ));
+
+            let fails = self.failures > 0;
+            self.failures = self.failures.saturating_sub(1);
+            Ok(Box::new(
+                Delay::new(Instant::now() + Duration::from_millis(WRITE_MS))
+                    .map_err(Error::from)
+                    .and_then(move |_| -> Result<ThermostatStatus> {
+                        if fails {
+                            Err(err_msg("thermostat offline"))
+                        } else {
+                            Ok(Thermostat::default().status(SystemTime::now()))
+                        }
+                    }),
+            ))
+        }
+    }
+
+    /// Who reports what, after how many milliseconds.
+    type Step = (u64, &'static str, PresenceEvent);
+
+    /// Runs `steps` against a household configured by `config`, then waits
+    /// `settle_ms` and returns the writes sent and the household's presence.
+    fn run(
+        config: &str,
+        failures: usize,
+        steps: Vec<Step>,
+        settle_ms: u64,
+    ) -> (Vec<String>, PresenceReport) {
+        let config: Config = ::toml::from_str(config).expect("config");
+        let writes = Arc::new(Mutex::new(Vec::new()));
+        let report = Arc::new(Mutex::new(None));
+        let (sent, reported) = (writes.clone(), report.clone());
+
+        System::run(move || {
+            let stub = Stub {
+                writes: sent,
+                failures,
+            }
+            .start();
+            let household = Household::with_recipients(
+                &config,
+                "default",
+                stub.clone().recipient(),
+                stub.recipient(),
+            )
+            .start();
+            let query = household.clone();
+
+            Arbiter::spawn(
+                stream::iter_ok(steps)
+                    .for_each(move |(after, person, event)| {
+                        let household = household.clone();
+                        Delay::new(Instant::now() + Duration::from_millis(after))
+                            .map_err(Error::from)
+                            .and_then(move |_| {
+                                household
+                                    .send(Traced::new(
+                                        RequestId::generate(),
+                                        ReportPresence {
+                                            person: person.to_owned(),
+                                            event,
+                                        },
+                                    ))
+                                    .map_err(|_| err_msg("mailbox error"))
+                                    .and_then(|resp| resp)
+                                    .map(|_| ())
+                            })
+                    })
+                    .and_then(move |_| {
+                        Delay::new(Instant::now() + Duration::from_millis(settle_ms))
+                            .map_err(Error::from)
+                    })
+                    .and_then(move |_| query.send(PresenceQuery).map_err(Error::from))
+                    .then(move |result| {
+                        *reported.lock().expect("report") = Some(result.expect("presence"));
+                        System::current().stop();
+                        Ok(())
+                    }),
+            );
+        });
+
+        let writes = writes.lock().expect("writes").clone();
+        let report = report.lock().expect("report").take().expect("report");
+        (writes, report)
+    }
+
+    #[test]
+    fn arriving_within_the_grace_period_writes_nothing() {
+        let (writes, report) = run(
+            "[presence]\ngrace_period = 1",
+            0,
+            vec![
+                (0, "alex", PresenceEvent::Leave),
+                (100, "alex", PresenceEvent::Arrive),
+            ],
+            1200,
+        );
+
+        assert!(writes.is_empty(), "{:?}", writes);
+        assert_eq!(report.occupied, Some(true));
+        assert!(!report.away_hold);
+        assert_eq!(report.away_hold_at, None);
+    }
+
+    #[test]
+    fn holds_and_resumes_every_thermostat() {
+        let (writes, report) = run(
+            "[presence]\ngrace_period = 0",
+            0,
+            vec![
+                (0, "alex", PresenceEvent::Leave),
+                (300, "alex", PresenceEvent::Arrive),
+            ],
+            300,
+        );
+
+        assert_eq!(
+            writes,
+            [
+                "upstairs hold away",
+                "downstairs hold away",
+                "upstairs resume",
+                "downstairs resume",
+            ]
+        );
+        assert!(!report.away_hold);
+        assert_eq!(report.last_result.as_deref(), Some("program resumed"));
+        assert_eq!(report.thermostats.len(), 2);
+    }
+
+    #[test]
+    fn arriving_during_the_away_hold_resumes_after_it() {
+        let (writes, report) = run(
+            "[presence]\ngrace_period = 0",
+            0,
+            vec![
+                (0, "alex", PresenceEvent::Leave),
+                (WRITE_MS / 2, "alex", PresenceEvent::Arrive),
+            ],
+            300,
+        );
+
+        assert_eq!(
+            writes,
+            [
+                "upstairs hold away",
+                "downstairs hold away",
+                "upstairs resume",
+                "downstairs resume",
+            ]
+        );
+        assert!(!report.away_hold);
+    }
+
+    #[test]
+    fn retries_a_failed_away_hold_after_the_grace_period() {
+        let (writes, report) = run(
+            "[presence]\ngrace_period = 0",
+            2,
+            vec![(0, "alex", PresenceEvent::Leave)],
+            400,
+        );
+
+        assert_eq!(writes.len(), 4, "{:?}", writes);
+        assert!(report.away_hold);
+        assert_eq!(report.last_result.as_deref(), Some("away hold set"));
+        assert_eq!(report.thermostats["upstairs"], "away hold set");
+    }
+
+    #[test]
+    fn reports_which_thermostats_failed() {
+        let (writes, report) = run(
+            "[presence]\ngrace_period = 0",
+            1,
+            vec![(0, "alex", PresenceEvent::Leave)],
+            300,
+        );
+
+        // Held on one thermostat, so an arrival resumes both rather than the
+        // grace period starting over.
+        assert_eq!(writes, ["upstairs hold away", "downstairs hold away"]);
+        assert!(report.away_hold);
+        assert_eq!(
+            report.last_result.as_deref(),
+            Some("failed to set the away hold on 1 of 2 thermostats")
+        );
+        assert_eq!(report.thermostats["upstairs"], "failed: thermostat offline");
+        assert_eq!(report.thermostats["downstairs"], "away hold set");
+    }
+
+    #[test]
+    fn resumes_a_persisted_away_hold_on_the_first_arrival() {
+        let dir = env::temp_dir().join(format!("castform-presence-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).expect("state dir");
+        let path = dir.join("default-presence.json");
+        fs::write(
+            &path,
+            r#"{"people": {"alex": {"home": false, "since": 0}}, "away_hold": true}"#,
+        )
+        .expect("state file");
+
+        let (writes, report) = run(
+            &format!("state_dir = {:?}", dir.display().to_string()),
+            0,
+            vec![(0, "alex", PresenceEvent::Arrive)],
+            300,
+        );
+        let state: State = load_json_state(&path, "presence state");
+        fs::remove_dir_all(&dir).expect("cleanup");
+
+        assert_eq!(writes, ["upstairs resume", "downstairs resume"]);
+        assert!(!report.away_hold);
+        assert!(!state.away_hold);
+        assert!(state.people["alex"].home);
+    }
+}
@@ -1,12 +1,48 @@
 use actix::Message;
 
-use response::EcobeeResponse;
+use response::{HealthStatus, RulesReport, SchedulesReport, ThermostatStatus, ThermostatSummary};
+use thermostat::Thermostat;
 use Result;
 
-pub enum EcobeeQuery {
-    Status,
+/// Status of the thermostat with the given identifier or name, or of the
+/// first one.
+pub struct StatusQuery(pub Option<String>);
+
+impl Message for StatusQuery {
+    type Result = Result<ThermostatStatus>;
+}
+
+/// Everything ecobee reported about the thermostat with the given identifier
+/// or name.
+pub struct RawQuery(pub String);
+
+impl Message for RawQuery {
+    type Result = Result<Thermostat>;
+}
+
+/// The account's thermostats, failing until they are known.
+pub struct ThermostatsQuery;
+
+impl Message for ThermostatsQuery {
+    type Result = Result<Vec<ThermostatSummary>>;
 }
 
-impl Message for EcobeeQuery {
-    type Result = Result<EcobeeResponse>;
+pub struct HealthQuery;
+
+impl Message for HealthQuery {
+    type Result = HealthStatus;
+}
+
+/// The latest evaluation of the rules.
+pub struct RulesQuery;
+
+impl Message for RulesQuery {
+    type Result = RulesReport;
+}
+
+/// The schedules and their next runs.
+pub struct SchedulesQuery;
+
+impl Message for SchedulesQuery {
+    type Result = SchedulesReport;
 }
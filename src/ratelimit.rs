@@ -0,0 +1,71 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// Target temperature changes arriving within this many milliseconds of
+    /// the first one are coalesced into a single ecobee write. 0 disables
+    /// coalescing.
+    pub coalesce_window_ms: u64,
+    /// Sustained ecobee requests per second.
+    pub requests_per_second: f64,
+    /// Requests that may be sent back to back before throttling kicks in.
+    pub burst: f64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            coalesce_window_ms: 750,
+            requests_per_second: 1.0,
+            burst: 10.0,
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket shared by every request sent to ecobee. Callers reserve a
+/// token up front and wait for the returned delay, so requests are throttled
+/// in arrival order rather than rejected.
+#[derive(Clone)]
+pub struct TokenBucket {
+    bucket: Arc<Mutex<Bucket>>,
+    rate: f64,
+    burst: f64,
+}
+
+impl TokenBucket {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        TokenBucket {
+            bucket: Arc::new(Mutex::new(Bucket {
+                tokens: config.burst,
+                updated: Instant::now(),
+            })),
+            rate: config.requests_per_second,
+            burst: config.burst,
+        }
+    }
+
+    /// Takes a token and returns how long the caller has to wait before the
+    /// token becomes valid.
+    pub fn reserve(&self) -> Duration {
+        let mut bucket = self.bucket.lock().expect("token bucket lock");
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.updated);
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst) - 1.0;
+        bucket.updated = now;
+
+        if bucket.tokens >= 0.0 || self.rate <= 0.0 {
+            Duration::from_millis(0)
+        } else {
+            Duration::from_millis((-bucket.tokens / self.rate * 1000.0).ceil() as u64)
+        }
+    }
+}
@@ -0,0 +1,264 @@
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use temperature::Temperature;
+use thermostat::{Event, Thermostat};
+
+/// ecobee's event type for holds.
+const HOLD: &str = "hold";
+
+/// The parts of a thermostat's state a write changed. Fields left `None`
+/// were not touched.
+#[derive(Clone, Debug, Default)]
+pub struct Expected {
+    pub hvac_mode: Option<String>,
+    /// Desired heat and cool setpoints.
+    pub setpoints: Option<(Temperature, Temperature)>,
+    /// Whether a hold is running.
+    pub hold: Option<bool>,
+    /// The comfort setting a running hold is for.
+    pub climate: Option<String>,
+    pub fan_min_on_time: Option<i32>,
+}
+
+impl Expected {
+    /// Takes over the fields `newer` sets.
+    pub fn merge(&mut self, newer: Expected) {
+        if newer.hvac_mode.is_some() {
+            self.hvac_mode = newer.hvac_mode;
+        }
+        if newer.setpoints.is_some() {
+            self.setpoints = newer.setpoints;
+        }
+        if newer.hold.is_some() {
+            self.hold = newer.hold;
+            // A temperature hold replaces a comfort setting hold and resuming
+            // ends both.
+            self.climate = newer.climate;
+        }
+        if newer.fan_min_on_time.is_some() {
+            self.fan_min_on_time = newer.fan_min_on_time;
+        }
+    }
+
+    /// Makes `thermostat` look like ecobee already reflects the write.
+    pub fn apply(&self, thermostat: &mut Thermostat) {
+        if let Some(ref mode) = self.hvac_mode {
+            thermostat.settings.hvac_mode = mode.clone();
+        }
+        if let Some((heat, cool)) = self.setpoints {
+            thermostat.runtime.desired_heat = heat;
+            thermostat.runtime.desired_cool = cool;
+        }
+        if let Some(hold) = self.hold {
+            thermostat.events.retain(|event| event.kind != HOLD);
+            if hold {
+                let (heat, cool) = self.setpoints.unwrap_or((
+                    thermostat.runtime.desired_heat,
+                    thermostat.runtime.desired_cool,
+                ));
+                thermostat.events.insert(
+                    0,
+                    Event {
+                        kind: HOLD.to_owned(),
+                        name: "castform".to_owned(),
+                        running: true,
+                        heat_hold_temp: heat,
+                        cool_hold_temp: cool,
+                        is_temperature_absolute: self.climate.is_none(),
+                        hold_climate_ref: self.climate.clone().unwrap_or_default(),
+                        ..Event::default()
+                    },
+                );
+            }
+        }
+        if let Some(minutes) = self.fan_min_on_time {
+            thermostat.settings.fan_min_on_time = minutes;
+        }
+    }
+
+    /// Names of the expected fields `thermostat` disagrees with.
+    pub fn mismatches(&self, thermostat: &Thermostat) -> Vec<&'static str> {
+        let mut fields = Vec::new();
+
+        if let Some(ref mode) = self.hvac_mode {
+            if thermostat.settings.hvac_mode != *mode {
+                fields.push("hvacMode");
+            }
+        }
+        if let Some((heat, cool)) = self.setpoints {
+            if thermostat.runtime.desired_heat != heat {
+                fields.push("desiredHeat");
+            }
+            if thermostat.runtime.desired_cool != cool {
+                fields.push("desiredCool");
+            }
+        }
+        if let Some(hold) = self.hold {
+            let running = thermostat
+                .events
+                .iter()
+                .find(|event| event.kind == HOLD && event.running);
+            if running.is_some() != hold {
+                fields.push("hold");
+            } else if let (Some(event), Some(climate)) = (running, self.climate.as_ref()) {
+                if event.hold_climate_ref != *climate {
+                    fields.push("holdClimateRef");
+                }
+            }
+        }
+        if let Some(minutes) = self.fan_min_on_time {
+            if thermostat.settings.fan_min_on_time != minutes {
+                fields.push("fanMinOnTime");
+            }
+        }
+
+        fields
+    }
+}
+
+/// A write ecobee accepted but the polls have not confirmed yet.
+pub struct PendingChange {
+    pub expected: Expected,
+    /// When the latest write contributing to `expected` was accepted.
+    pub since: Instant,
+}
+
+/// ecobee kept reporting a different state than castform wrote, e.g.
+/// because someone changed the thermostat at the wall.
+#[derive(Serialize, Clone)]
+pub struct Conflict {
+    pub thermostat: String,
+    /// Seconds since the Unix epoch.
+    pub at: u64,
+    pub fields: Vec<&'static str>,
+}
+
+impl Conflict {
+    pub fn new(thermostat: &str, fields: Vec<&'static str>) -> Self {
+        Conflict {
+            thermostat: thermostat.to_owned(),
+            at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or(0),
+            fields,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn thermostat() -> Thermostat {
+        let mut thermostat = Thermostat::default();
+        thermostat.settings.hvac_mode = "heat".to_owned();
+        thermostat.runtime.desired_heat = Temperature::from_tenths(680);
+        thermostat.runtime.desired_cool = Temperature::from_tenths(760);
+        thermostat
+    }
+
+    fn hold(heat: i32, cool: i32) -> Expected {
+        Expected {
+            setpoints: Some((
+                Temperature::from_tenths(heat),
+                Temperature::from_tenths(cool),
+            )),
+            hold: Some(true),
+            ..Expected::default()
+        }
+    }
+
+    #[test]
+    fn merge_keeps_fields_the_newer_write_leaves_alone() {
+        let mut expected = Expected {
+            hvac_mode: Some("cool".to_owned()),
+            ..hold(700, 760)
+        };
+        expected.merge(Expected {
+            fan_min_on_time: Some(15),
+            ..Expected::default()
+        });
+        expected.merge(hold(690, 750));
+
+        assert_eq!(expected.hvac_mode, Some("cool".to_owned()));
+        assert_eq!(
+            expected.setpoints,
+            Some((Temperature::from_tenths(690), Temperature::from_tenths(750)))
+        );
+        assert_eq!(expected.hold, Some(true));
+        assert_eq!(expected.fan_min_on_time, Some(15));
+    }
+
+    #[test]
+    fn merge_replaces_the_climate_with_the_hold() {
+        let mut expected = Expected {
+            hold: Some(true),
+            climate: Some("away".to_owned()),
+            ..Expected::default()
+        };
+        expected.merge(hold(700, 760));
+        assert_eq!(expected.climate, None);
+
+        expected.merge(Expected {
+            hold: Some(false),
+            ..Expected::default()
+        });
+        assert_eq!(expected.hold, Some(false));
+        assert_eq!(expected.climate, None);
+    }
+
+    #[test]
+    fn applied_changes_do_not_mismatch() {
+        let expected = Expected {
+            hvac_mode: Some("cool".to_owned()),
+            fan_min_on_time: Some(20),
+            ..hold(700, 760)
+        };
+        let mut thermostat = thermostat();
+        assert_eq!(
+            expected.mismatches(&thermostat),
+            vec!["hvacMode", "desiredHeat", "hold", "fanMinOnTime"]
+        );
+
+        expected.apply(&mut thermostat);
+        assert!(expected.mismatches(&thermostat).is_empty());
+        assert_eq!(thermostat.events.len(), 1);
+        assert!(thermostat.events[0].is_temperature_absolute);
+        assert_eq!(
+            thermostat.events[0].heat_hold_temp,
+            Temperature::from_tenths(700)
+        );
+    }
+
+    #[test]
+    fn climate_holds_compare_the_climate_ref() {
+        let expected = Expected {
+            hold: Some(true),
+            climate: Some("away".to_owned()),
+            ..Expected::default()
+        };
+        let mut thermostat = thermostat();
+        expected.apply(&mut thermostat);
+        assert!(expected.mismatches(&thermostat).is_empty());
+        assert_eq!(thermostat.events[0].hold_climate_ref, "away");
+
+        thermostat.events[0].hold_climate_ref = "sleep".to_owned();
+        assert_eq!(expected.mismatches(&thermostat), vec!["holdClimateRef"]);
+    }
+
+    #[test]
+    fn resuming_clears_the_hold() {
+        let mut thermostat = thermostat();
+        hold(700, 760).apply(&mut thermostat);
+
+        let resume = Expected {
+            hold: Some(false),
+            ..Expected::default()
+        };
+        assert_eq!(resume.mismatches(&thermostat), vec!["hold"]);
+        resume.apply(&mut thermostat);
+        assert!(thermostat.events.is_empty());
+        assert!(resume.mismatches(&thermostat).is_empty());
+    }
+}
@@ -0,0 +1,195 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::time::{Duration, SystemTime};
+
+use actix::actors::signal::{ProcessSignals, Signal, SignalType, Subscribe};
+use actix::{Actor, AsyncContext, Context, Handler, SystemService};
+use log::LevelFilter;
+use toml::Value;
+
+use backend::Reconfigure;
+use config::Config;
+use logging::LogHandle;
+use server::{Account, ServerSettings, SharedSettings};
+use Result;
+
+/// Top level config keys whose changes are applied without a restart.
+const LIVE_KEYS: &[&str] = &[
+    "auth",
+    "log_level",
+    "poll_interval",
+    "presence",
+    "rate_limit",
+    "ready_max_age",
+    "retry",
+    "rules",
+    "schedules",
+    "units",
+];
+
+/// Seconds between checks of the config file's modification time when
+/// watching.
+const WATCH_INTERVAL: u64 = 5;
+
+/// Re-reads the config file on SIGHUP, and whenever it changes if watching is
+/// enabled. Settings in `LIVE_KEYS` are pushed to the running actors and the
+/// HTTP server, changes to anything else are reported as needing a restart.
+pub struct ConfigWatcher {
+    path: String,
+    /// The config the process was started with.
+    running: Config,
+    /// The most recently applied config.
+    current: Config,
+    accounts: Vec<Account>,
+    settings: SharedSettings,
+    log: LogHandle,
+    /// Level given with `--log-level`, which wins over the config file.
+    log_level: Option<LevelFilter>,
+    watch: bool,
+    modified: Option<SystemTime>,
+}
+
+impl ConfigWatcher {
+    pub fn new(
+        path: &str,
+        config: Config,
+        accounts: Vec<Account>,
+        settings: SharedSettings,
+        log: LogHandle,
+        log_level: Option<LevelFilter>,
+        watch: bool,
+    ) -> Self {
+        ConfigWatcher {
+            path: path.to_owned(),
+            running: config.clone(),
+            current: config,
+            accounts,
+            settings,
+            log,
+            log_level,
+            watch,
+            modified: modified(path),
+        }
+    }
+
+    fn reload(&mut self) {
+        let config = match Config::load(&self.path).and_then(|config| {
+            config.validate()?;
+            Ok(config)
+        }) {
+            Ok(config) => config,
+            Err(e) => {
+                error!(
+                    "keeping the current config, reloading {} failed: {}",
+                    self.path, e
+                );
+                return;
+            }
+        };
+
+        let (applied, restart) = match (
+            changed_keys(&self.current, &config),
+            changed_keys(&self.running, &config),
+        ) {
+            (Ok(applied), Ok(restart)) => (applied, restart),
+            (Err(e), _) | (_, Err(e)) => {
+                error!("failed to compare configs: {}", e);
+                return;
+            }
+        };
+        let applied = applied
+            .into_iter()
+            .filter(|key| LIVE_KEYS.contains(&key.as_str()))
+            .collect::<Vec<_>>();
+        let restart = restart
+            .into_iter()
+            .filter(|key| !LIVE_KEYS.contains(&key.as_str()))
+            .collect::<Vec<_>>();
+
+        *self.settings.write().expect("settings lock") = ServerSettings::from_config(&config);
+        for account in &self.accounts {
+            account.backend.do_send(Reconfigure(config.clone()));
+            account.presence.do_send(Reconfigure(config.clone()));
+        }
+
+        let level = self.log_level.or_else(|| {
+            config
+                .log_level
+                .as_ref()
+                .and_then(|level| level.parse().ok())
+        });
+        self.log.set_level(level.unwrap_or(LevelFilter::Info));
+
+        if applied.is_empty() {
+            info!("reloaded {}, no live settings changed", self.path);
+        } else {
+            info!("reloaded {}, applied {}", self.path, applied.join(", "));
+        }
+        if !restart.is_empty() {
+            warn!(
+                "changes to {} take effect after a restart",
+                restart.join(", ")
+            );
+        }
+
+        self.current = config;
+    }
+}
+
+impl Actor for ConfigWatcher {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ProcessSignals::from_registry().do_send(Subscribe(ctx.address().recipient()));
+
+        if self.watch {
+            ctx.run_interval(Duration::from_secs(WATCH_INTERVAL), |watcher, _| {
+                let modified = modified(&watcher.path);
+                if modified.is_some() && modified != watcher.modified {
+                    watcher.modified = modified;
+                    info!("{} changed, reloading", watcher.path);
+                    watcher.reload();
+                }
+            });
+        }
+    }
+}
+
+impl Handler<Signal> for ConfigWatcher {
+    type Result = ();
+
+    fn handle(&mut self, msg: Signal, _: &mut Context<Self>) {
+        if msg.0 == SignalType::Hup {
+            info!("SIGHUP received, reloading {}", self.path);
+            self.reload();
+        }
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Names of the top level keys that differ between two configs. Values are
+/// never logged, so secrets only show up by name.
+fn changed_keys(old: &Config, new: &Config) -> Result<Vec<String>> {
+    let old = match Value::try_from(old)? {
+        Value::Table(table) => table,
+        _ => unreachable!("config serializes to a table"),
+    };
+    let new = match Value::try_from(new)? {
+        Value::Table(table) => table,
+        _ => unreachable!("config serializes to a table"),
+    };
+
+    Ok(old
+        .keys()
+        .chain(new.keys())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .filter(|key| old.get(*key) != new.get(*key))
+        .cloned()
+        .collect())
+}
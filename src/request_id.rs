@@ -0,0 +1,95 @@
+use std::fmt;
+
+use actix::Message;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::{Middleware, Response, Started};
+use actix_web::{FromRequest, HttpRequest, HttpResponse, Result};
+use rand::{thread_rng, Rng};
+
+/// Header carrying the request ID, both accepted from clients and echoed back.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Identifies an incoming HTTP call in the logs of everything it triggers,
+/// including the ecobee requests sent on its behalf.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RequestId(String);
+
+impl RequestId {
+    pub fn generate() -> Self {
+        RequestId(format!("{:016x}", thread_rng().gen::<u64>()))
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Middleware assigning a `RequestId` to every HTTP request. A well formed
+/// `X-Request-Id` sent by the client is reused, otherwise a new one is made.
+pub struct AssignRequestId;
+
+impl<S> Middleware<S> for AssignRequestId {
+    fn start(&self, req: &HttpRequest<S>) -> Result<Started> {
+        let id = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|value| {
+                !value.is_empty()
+                    && value.len() <= 64
+                    && value
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            })
+            .map(|value| RequestId(value.to_owned()))
+            .unwrap_or_else(RequestId::generate);
+
+        req.extensions_mut().insert(id);
+        Ok(Started::Done)
+    }
+
+    fn response(&self, req: &HttpRequest<S>, mut resp: HttpResponse) -> Result<Response> {
+        if let Some(id) = req.extensions().get::<RequestId>() {
+            if let Ok(value) = HeaderValue::from_str(&id.0) {
+                resp.headers_mut()
+                    .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+            }
+        }
+
+        Ok(Response::Done(resp))
+    }
+}
+
+impl<S> FromRequest<S> for RequestId {
+    type Config = ();
+    type Result = RequestId;
+
+    fn from_request(req: &HttpRequest<S>, _: &Self::Config) -> Self::Result {
+        req.extensions()
+            .get::<RequestId>()
+            .cloned()
+            .unwrap_or_else(RequestId::generate)
+    }
+}
+
+/// Wraps a message with the ID of the request that caused it, so the
+/// receiving actor can tag the work it does on the request's behalf.
+pub struct Traced<M> {
+    pub request_id: RequestId,
+    pub message: M,
+}
+
+impl<M> Traced<M> {
+    pub fn new(request_id: RequestId, message: M) -> Self {
+        Traced {
+            request_id,
+            message,
+        }
+    }
+}
+
+impl<M: Message> Message for Traced<M> {
+    type Result = M::Result;
+}
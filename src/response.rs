@@ -1,33 +1,219 @@
-#[derive(Serialize)]
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ecobee::hvac_mode_index;
+use reconcile::Conflict;
+use temperature::{Temperature, Units};
+use thermostat::Thermostat;
+
+/// Thermostat state as presented to HomeKit, rendered in the requested
+/// temperature scale.
+#[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct EcobeeStatus {
-    target_heating_cooling_state: u8,
-    target_temperature: f32,
-    target_relative_humidity: f32,
-    current_heating_cooling_state: u8,
-    current_temperature: f32,
-    current_relative_humidity: f32,
-}
-
-impl EcobeeStatus {
-    pub fn new(
-        mode: u8,
-        target: f32,
-        current: f32,
-        humidity: f32,
-        target_humidity: f32,
-    ) -> EcobeeStatus {
+    pub target_heating_cooling_state: u8,
+    pub target_temperature: f32,
+    pub target_relative_humidity: f32,
+    pub current_heating_cooling_state: u8,
+    pub current_temperature: f32,
+    pub current_relative_humidity: f32,
+    /// When castform fetched this state, in seconds since the Unix epoch.
+    pub updated_at: u64,
+    /// Seconds since castform fetched this state.
+    pub age: u64,
+    pub last_modified: String,
+    pub last_status_modified: String,
+}
+
+/// A thermostat registered with the ecobee account.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ThermostatSummary {
+    pub identifier: String,
+    pub name: String,
+    pub hvac_mode: String,
+}
+
+/// Scale-independent snapshot of a thermostat, see `in_units`.
+#[derive(Clone)]
+pub struct ThermostatStatus {
+    mode: u8,
+    target: Temperature,
+    current: Temperature,
+    humidity: f32,
+    target_humidity: f32,
+    use_celsius: bool,
+    updated: SystemTime,
+    last_modified: String,
+    last_status_modified: String,
+}
+
+impl ThermostatStatus {
+    /// The state of `thermostat` as fetched at `updated`. The target is the
+    /// middle of its heating and cooling setpoints.
+    pub fn from(thermostat: &Thermostat, updated: SystemTime) -> ThermostatStatus {
+        let runtime = &thermostat.runtime;
+        let settings = &thermostat.settings;
+
+        ThermostatStatus {
+            mode: hvac_mode_index(&settings.hvac_mode),
+            target: Temperature::from_tenths(
+                (runtime.desired_heat.tenths() + runtime.desired_cool.tenths()) / 2,
+            ),
+            current: runtime.actual_temperature,
+            humidity: runtime.actual_humidity as f32,
+            target_humidity: runtime.desired_humidity as f32 / 100.0,
+            use_celsius: settings.use_celsius,
+            updated,
+            last_modified: thermostat.last_modified.clone(),
+            last_status_modified: runtime.last_status_modified.clone(),
+        }
+    }
+
+    /// Seconds since castform fetched this state from ecobee.
+    pub fn age(&self) -> u64 {
+        self.updated
+            .elapsed()
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or(0)
+    }
+
+    pub fn with_target_heating_cooling_state(mut self, mode: u8) -> ThermostatStatus {
+        self.mode = mode;
+        self
+    }
+
+    pub fn with_target_temperature(mut self, target: Temperature) -> ThermostatStatus {
+        self.target = target;
+        self
+    }
+
+    pub fn in_units(&self, units: Units) -> EcobeeStatus {
         EcobeeStatus {
-            target_heating_cooling_state: mode,
-            target_temperature: target,
-            target_relative_humidity: target_humidity,
-            current_heating_cooling_state: mode,
-            current_temperature: current,
-            current_relative_humidity: humidity,
+            target_heating_cooling_state: self.mode,
+            target_temperature: self.target.display(units, self.use_celsius),
+            target_relative_humidity: self.target_humidity,
+            current_heating_cooling_state: self.mode,
+            current_temperature: self.current.display(units, self.use_celsius),
+            current_relative_humidity: self.humidity,
+            updated_at: self
+                .updated
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or(0),
+            age: self.age(),
+            last_modified: self.last_modified.clone(),
+            last_status_modified: self.last_status_modified.clone(),
         }
     }
 }
 
-pub enum EcobeeResponse {
-    Status(EcobeeStatus),
+/// Bridge health as seen by `EcobeeActor`. Times are seconds, timestamps are
+/// seconds since the Unix epoch.
+#[derive(Serialize)]
+pub struct HealthStatus {
+    pub authenticated: bool,
+    pub token_age: Option<u64>,
+    pub last_poll: Option<u64>,
+    pub last_poll_age: Option<u64>,
+    pub last_error: Option<LastError>,
+    pub thermostats: usize,
+    /// State of the circuit breaker guarding ecobee calls.
+    pub circuit: &'static str,
+    /// Accepted writes not yet confirmed by a poll.
+    pub pending_changes: usize,
+    /// Writes ecobee's state kept disagreeing with, by thermostat.
+    pub conflicts: Vec<Conflict>,
+}
+
+#[derive(Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    #[serde(flatten)]
+    pub health: HealthStatus,
+}
+
+/// Readiness of every ecobee account, ready only when all of them are.
+#[derive(Serialize)]
+pub struct ReadinessReport {
+    pub ready: bool,
+    pub accounts: BTreeMap<String, Readiness>,
+}
+
+/// The latest evaluation of an account's `[[rules]]`.
+#[derive(Serialize)]
+pub struct RulesReport {
+    pub evaluated_at: Option<u64>,
+    pub rules: Vec<RuleReport>,
+}
+
+#[derive(Serialize)]
+pub struct RuleReport {
+    pub name: String,
+    /// The thermostat the rule watches, the account's first one if unset.
+    pub thermostat: Option<String>,
+    /// One of `idle`, `waiting`, `in_effect`, `cooling_down`, `ran` or
+    /// `dry_run`.
+    pub state: &'static str,
+    pub dry_run: bool,
+    /// Whether each condition held, in config order.
+    pub conditions: Vec<bool>,
+    pub matched_since: Option<u64>,
+    pub last_run: Option<u64>,
+    pub last_result: Option<String>,
+}
+
+/// An account's `[[schedules]]` and when they run next.
+#[derive(Serialize)]
+pub struct SchedulesReport {
+    /// The first thermostat's, unknown until it has been polled.
+    pub time_zone: Option<String>,
+    pub schedules: Vec<ScheduleReport>,
+}
+
+#[derive(Serialize)]
+pub struct ScheduleReport {
+    pub name: String,
+    /// The thermostat the schedule changes, the account's first one if
+    /// unset.
+    pub thermostat: Option<String>,
+    /// That thermostat's, which `at` is in.
+    pub time_zone: Option<String>,
+    pub days: Vec<String>,
+    pub at: String,
+    pub actions: Vec<String>,
+    pub next_run: Option<u64>,
+    /// `next_run` in the thermostat's time zone, as RFC 3339.
+    pub next_run_local: Option<String>,
+    pub last_run: Option<u64>,
+    pub last_result: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct PresenceReport {
+    /// Whether anyone is home, `null` until someone reported.
+    pub occupied: Option<bool>,
+    pub people: BTreeMap<String, PersonReport>,
+    /// Whether castform is holding the away comfort setting.
+    pub away_hold: bool,
+    /// When the away hold will be set, while the grace period runs.
+    pub away_hold_at: Option<u64>,
+    pub grace_period: u64,
+    pub last_result: Option<String>,
+    /// How the last away hold or resume went on each thermostat, by
+    /// identifier.
+    pub thermostats: BTreeMap<String, String>,
+}
+
+#[derive(Serialize)]
+pub struct PersonReport {
+    pub home: bool,
+    /// Since when `home` holds.
+    pub since: u64,
+}
+
+#[derive(Serialize)]
+pub struct LastError {
+    pub at: u64,
+    pub message: String,
 }
@@ -0,0 +1,218 @@
+use std::cmp;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rand::{thread_rng, Rng};
+
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct RetryConfig {
+    /// Total attempts per request, including the first one.
+    pub max_attempts: u32,
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+    /// Consecutive failures that open the circuit breaker.
+    pub failure_threshold: u32,
+    /// Seconds the breaker stays open before letting a trial request through.
+    pub reset_after: u64,
+    /// Seconds to wait for ecobee to answer an attempt before counting it as
+    /// failed.
+    pub request_timeout: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_attempts: 3,
+            base_delay_ms: 500,
+            max_delay_ms: 10_000,
+            failure_threshold: 5,
+            reset_after: 60,
+            request_timeout: 30,
+        }
+    }
+}
+
+impl RetryConfig {
+    /// Delay before retrying after `attempt` failed attempts, using
+    /// exponential backoff with full jitter.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let ceiling = self
+            .base_delay_ms
+            .saturating_mul(1 << cmp::min(attempt, 16))
+            .min(self.max_delay_ms);
+
+        Duration::from_millis(thread_rng().gen_range(0, ceiling + 1))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// A trial request went out at `since`.
+    HalfOpen {
+        since: Instant,
+    },
+}
+
+/// Circuit breaker shared by every request `EcobeeActor` makes. Once
+/// `failure_threshold` consecutive requests fail with transient errors, calls
+/// are rejected without touching the network for `reset_after` seconds. After
+/// that a single trial request decides whether to close the circuit again. A
+/// trial that never reports back, e.g. because its caller went away, is
+/// replaced by another one after `reset_after` seconds.
+#[derive(Clone)]
+pub struct CircuitBreaker {
+    state: Arc<Mutex<State>>,
+    failure_threshold: u32,
+    reset_after: Duration,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: &RetryConfig) -> Self {
+        CircuitBreaker {
+            state: Arc::new(Mutex::new(State::Closed { failures: 0 })),
+            failure_threshold: config.failure_threshold,
+            reset_after: Duration::from_secs(config.reset_after),
+        }
+    }
+
+    /// Returns whether a request may be sent right now.
+    pub fn allow(&self) -> bool {
+        let mut state = self.state.lock().expect("circuit breaker lock");
+
+        let now = Instant::now();
+
+        match *state {
+            State::Closed { .. } => true,
+            State::Open { until } if now >= until => {
+                *state = State::HalfOpen { since: now };
+                true
+            }
+            State::HalfOpen { since } if now >= since + self.reset_after => {
+                *state = State::HalfOpen { since: now };
+                true
+            }
+            State::Open { .. } | State::HalfOpen { .. } => false,
+        }
+    }
+
+    pub fn record_success(&self) {
+        *self.state.lock().expect("circuit breaker lock") = State::Closed { failures: 0 };
+    }
+
+    pub fn record_failure(&self) {
+        let mut state = self.state.lock().expect("circuit breaker lock");
+        let failures = match *state {
+            State::Closed { failures } => failures + 1,
+            State::Open { .. } | State::HalfOpen { .. } => self.failure_threshold,
+        };
+
+        *state = if failures >= self.failure_threshold {
+            State::Open {
+                until: Instant::now() + self.reset_after,
+            }
+        } else {
+            State::Closed { failures }
+        };
+    }
+
+    /// Human readable state for health reporting.
+    pub fn describe(&self) -> &'static str {
+        match *self.state.lock().expect("circuit breaker lock") {
+            State::Closed { .. } => "closed",
+            State::Open { .. } => "open",
+            State::HalfOpen { .. } => "half_open",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(reset_after: u64) -> CircuitBreaker {
+        CircuitBreaker::new(&RetryConfig {
+            failure_threshold: 2,
+            reset_after,
+            ..RetryConfig::default()
+        })
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let breaker = breaker(60);
+
+        breaker.record_failure();
+        assert!(breaker.allow());
+        breaker.record_success();
+        breaker.record_failure();
+        assert_eq!(breaker.describe(), "closed");
+        breaker.record_failure();
+        assert_eq!(breaker.describe(), "open");
+        assert!(!breaker.allow());
+    }
+
+    #[test]
+    fn lets_a_single_trial_through_once_reset() {
+        let breaker = breaker(0);
+        breaker.record_failure();
+        breaker.record_failure();
+
+        assert!(breaker.allow());
+        assert_eq!(breaker.describe(), "half_open");
+        breaker.record_success();
+        assert_eq!(breaker.describe(), "closed");
+    }
+
+    #[test]
+    fn failed_trial_opens_again() {
+        let breaker = breaker(0);
+        breaker.record_failure();
+        breaker.record_failure();
+        assert!(breaker.allow());
+
+        breaker.record_failure();
+        assert_eq!(breaker.describe(), "open");
+    }
+
+    #[test]
+    fn blocks_while_a_trial_is_out() {
+        let breaker = breaker(60);
+        *breaker.state.lock().unwrap() = State::HalfOpen {
+            since: Instant::now(),
+        };
+
+        assert!(!breaker.allow());
+    }
+
+    #[test]
+    fn replaces_a_trial_that_never_reported_back() {
+        let breaker = breaker(60);
+        *breaker.state.lock().unwrap() = State::HalfOpen {
+            since: Instant::now() - Duration::from_secs(61),
+        };
+
+        assert!(breaker.allow());
+        assert!(!breaker.allow());
+    }
+
+    #[test]
+    fn backoff_stays_below_the_cap() {
+        let config = RetryConfig {
+            base_delay_ms: 100,
+            max_delay_ms: 1000,
+            ..RetryConfig::default()
+        };
+
+        assert!(config.backoff(1) <= Duration::from_millis(200));
+        for attempt in 1..40 {
+            assert!(config.backoff(attempt) <= Duration::from_millis(1000));
+        }
+    }
+}
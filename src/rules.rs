@@ -0,0 +1,708 @@
+//! Local automation rules from the `[[rules]]` config tables, evaluated
+//! against every fresh thermostat state. A rule whose conditions all hold
+//! runs its actions through the backend's `ChangeThermostat` handling, like
+//! a HomeKit change would.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+use actix::{Actor, Addr, Arbiter, Context, Handler, Message};
+use failure::err_msg;
+use futures::{stream, Future, Stream};
+
+use backend::{ChangeThermostat, HoldType, TargetedChange, MAX_FAN_MIN_ON_TIME};
+use config::{is_valid_name, Config};
+use ecobee::hvac_mode_index;
+use request_id::{RequestId, Traced};
+use response::{RuleReport, RulesReport};
+use state::unix_time;
+use temperature::{Temperature, Units};
+use thermostat::Thermostat;
+
+/// ecobee's placeholder for readings it does not have.
+const MISSING_READING: i32 = -5002;
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct RuleConfig {
+    pub name: String,
+    /// Identifier or name of the thermostat the rule watches and changes,
+    /// the account's first one if unset.
+    #[serde(default)]
+    pub thermostat: Option<String>,
+    /// All of them must hold for the rule to run.
+    pub conditions: Vec<Condition>,
+    /// Run in order, stopping at the first one that fails.
+    pub actions: Vec<RuleAction>,
+    /// Seconds the conditions must hold before the actions run.
+    #[serde(default)]
+    pub after: u64,
+    /// Seconds after running before the rule may run again.
+    #[serde(default = "default_cooldown")]
+    pub cooldown: u64,
+    /// Only log and report what the rule would do.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+fn default_cooldown() -> u64 {
+    60 * 60
+}
+
+/// A check against the thermostat's state. Temperatures are in the
+/// configured `units`.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "check", rename_all = "snake_case")]
+pub enum Condition {
+    OutdoorTemperature {
+        below: Option<f32>,
+        above: Option<f32>,
+    },
+    IndoorTemperature {
+        below: Option<f32>,
+        above: Option<f32>,
+    },
+    /// Indoor relative humidity in percent.
+    Humidity {
+        below: Option<f32>,
+        above: Option<f32>,
+    },
+    /// Whether the sensor with this name or ID detects someone.
+    Occupancy {
+        sensor: String,
+        occupied: bool,
+    },
+    HvacMode {
+        mode: String,
+    },
+}
+
+/// A change run by a rule or a schedule.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum RuleAction {
+    HvacMode {
+        mode: String,
+    },
+    /// A hold at this temperature, in the configured `units`.
+    Temperature {
+        value: f32,
+        #[serde(default)]
+        hold: HoldType,
+    },
+    /// A hold of the comfort setting with this climate ref, e.g. `away`.
+    Climate {
+        climate: String,
+        #[serde(default)]
+        hold: HoldType,
+    },
+    /// Minutes per hour the fan runs at least.
+    FanMinOnTime {
+        minutes: u8,
+    },
+    ResumeProgram,
+}
+
+/// Adds the problems with `rules` to `problems`, for `Config::validate`.
+pub fn validate(rules: &[RuleConfig], problems: &mut Vec<String>) {
+    let mut names = Vec::new();
+
+    for rule in rules {
+        let owner = format!("rule `{}`", rule.name);
+
+        if !is_valid_name(&rule.name) {
+            problems.push(format!(
+                "rule name `{}` must be non-empty and only use letters, digits, `-` and `_`",
+                rule.name
+            ));
+        } else if names.contains(&&rule.name) {
+            problems.push(format!("rule name `{}` is used twice", rule.name));
+        }
+        names.push(&rule.name);
+
+        if rule.thermostat.as_deref() == Some("") {
+            problems.push(format!("{} names an empty thermostat", owner));
+        }
+        if rule.conditions.is_empty() {
+            problems.push(format!("{} needs at least one condition", owner));
+        }
+
+        for condition in &rule.conditions {
+            match *condition {
+                Condition::OutdoorTemperature { below, above }
+                | Condition::IndoorTemperature { below, above }
+                | Condition::Humidity { below, above } => {
+                    if below.is_none() && above.is_none() {
+                        problems.push(format!(
+                            "{} compares against nothing, set `below` or `above`",
+                            owner
+                        ));
+                    }
+                }
+                Condition::HvacMode { ref mode } => check_hvac_mode(&owner, mode, problems),
+                Condition::Occupancy { .. } => (),
+            }
+        }
+
+        validate_actions(&owner, &rule.actions, problems);
+    }
+}
+
+/// Adds the problems with the `actions` of `owner`, e.g. "rule `x`", to
+/// `problems`.
+pub fn validate_actions(owner: &str, actions: &[RuleAction], problems: &mut Vec<String>) {
+    if actions.is_empty() {
+        problems.push(format!("{} needs at least one action", owner));
+    }
+
+    for action in actions {
+        match *action {
+            RuleAction::HvacMode { ref mode } => check_hvac_mode(owner, mode, problems),
+            RuleAction::Temperature { value, .. } if !value.is_finite() => {
+                problems.push(format!("{} sets a temperature that is not a number", owner))
+            }
+            RuleAction::FanMinOnTime { minutes } if minutes > MAX_FAN_MIN_ON_TIME => {
+                problems.push(format!(
+                    "{} runs the fan {} minutes per hour, at most {} are supported",
+                    owner, minutes, MAX_FAN_MIN_ON_TIME
+                ))
+            }
+            _ => (),
+        }
+    }
+}
+
+fn check_hvac_mode(owner: &str, mode: &str, problems: &mut Vec<String>) {
+    if hvac_mode_index(mode) == 0 && mode != "off" {
+        problems.push(format!(
+            "{} uses unknown hvac mode `{}`, expected off, heat, cool or auto",
+            owner, mode
+        ));
+    }
+}
+
+/// Compares an optional reading against the bounds, false without one.
+fn within(value: Option<f32>, below: Option<f32>, above: Option<f32>) -> bool {
+    value.is_some_and(|value| {
+        below.is_none_or(|below| value < below) && above.is_none_or(|above| value > above)
+    })
+}
+
+fn reading(temperature: Temperature, units: Units) -> Option<f32> {
+    if temperature.tenths() == MISSING_READING {
+        None
+    } else {
+        Some(temperature.in_units(units))
+    }
+}
+
+/// Whether a hold is in effect, and of which climate if it is a comfort
+/// setting hold.
+fn running_hold(thermostat: &Thermostat) -> Option<&str> {
+    thermostat
+        .events
+        .iter()
+        .find(|event| event.kind == "hold" && event.running)
+        .map(|event| event.hold_climate_ref.as_str())
+}
+
+impl Condition {
+    fn holds(&self, thermostat: &Thermostat, units: Units) -> bool {
+        match *self {
+            Condition::OutdoorTemperature { below, above } => {
+                let outdoor = thermostat
+                    .weather
+                    .as_ref()
+                    .and_then(|weather| weather.forecasts.first())
+                    .and_then(|forecast| reading(forecast.temperature, units));
+                within(outdoor, below, above)
+            }
+            Condition::IndoorTemperature { below, above } => within(
+                reading(thermostat.runtime.actual_temperature, units),
+                below,
+                above,
+            ),
+            Condition::Humidity { below, above } => within(
+                Some(thermostat.runtime.actual_humidity as f32),
+                below,
+                above,
+            ),
+            Condition::Occupancy {
+                ref sensor,
+                occupied,
+            } => thermostat
+                .remote_sensors
+                .iter()
+                .find(|candidate| {
+                    candidate.id == *sensor || candidate.name.eq_ignore_ascii_case(sensor)
+                })
+                .and_then(|sensor| {
+                    sensor
+                        .capability
+                        .iter()
+                        .find(|capability| capability.kind == "occupancy")
+                })
+                .is_some_and(|capability| (capability.value == "true") == occupied),
+            Condition::HvacMode { ref mode } => thermostat.settings.hvac_mode == *mode,
+        }
+    }
+}
+
+impl RuleAction {
+    pub fn change(&self, units: Units) -> ChangeThermostat {
+        match *self {
+            RuleAction::HvacMode { ref mode } => ChangeThermostat::HvacMode(hvac_mode_index(mode)),
+            RuleAction::Temperature { value, hold } => {
+                ChangeThermostat::Temperature(Temperature::from_units(value, units), hold)
+            }
+            RuleAction::Climate { ref climate, hold } => {
+                ChangeThermostat::Climate(climate.clone(), hold)
+            }
+            RuleAction::FanMinOnTime { minutes } => ChangeThermostat::FanMinOnTime(minutes),
+            RuleAction::ResumeProgram => ChangeThermostat::ResumeProgram,
+        }
+    }
+
+    /// Whether the thermostat already is how the action would leave it, so
+    /// running it again would only cost an ecobee write.
+    fn in_effect(&self, thermostat: &Thermostat, units: Units) -> bool {
+        match *self {
+            RuleAction::HvacMode { ref mode } => thermostat.settings.hvac_mode == *mode,
+            RuleAction::Temperature { value, .. } => {
+                let runtime = &thermostat.runtime;
+                running_hold(thermostat).is_some()
+                    && thermostat
+                        .settings
+                        .hold_setpoints(Temperature::from_units(value, units))
+                        .ok()
+                        == Some((runtime.desired_heat, runtime.desired_cool))
+            }
+            RuleAction::Climate { ref climate, .. } => running_hold(thermostat) == Some(climate),
+            RuleAction::FanMinOnTime { minutes } => {
+                thermostat.settings.fan_min_on_time == i32::from(minutes)
+            }
+            RuleAction::ResumeProgram => running_hold(thermostat).is_none(),
+        }
+    }
+}
+
+impl fmt::Display for RuleAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RuleAction::HvacMode { ref mode } => write!(f, "switch to {}", mode),
+            RuleAction::Temperature { value, hold } => {
+                write!(f, "hold {}° {}", value, describe_hold(hold))
+            }
+            RuleAction::Climate { ref climate, hold } => {
+                write!(f, "hold {} {}", climate, describe_hold(hold))
+            }
+            RuleAction::FanMinOnTime { minutes } => {
+                write!(f, "run the fan {} minutes per hour", minutes)
+            }
+            RuleAction::ResumeProgram => write!(f, "resume the program"),
+        }
+    }
+}
+
+fn describe_hold(hold: HoldType) -> &'static str {
+    match hold {
+        HoldType::Indefinite => "indefinitely",
+        HoldType::NextTransition => "until the next transition",
+    }
+}
+
+/// Where a rule stood after the last evaluation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum RuleState {
+    /// Its conditions don't hold.
+    Idle,
+    /// Its conditions hold, but not for `after` seconds yet.
+    Waiting,
+    /// Its actions are already in effect.
+    InEffect,
+    /// It ran less than `cooldown` seconds ago.
+    CoolingDown,
+    Ran,
+    /// It would have run, but is a dry run.
+    DryRun,
+}
+
+impl RuleState {
+    fn describe(self) -> &'static str {
+        match self {
+            RuleState::Idle => "idle",
+            RuleState::Waiting => "waiting",
+            RuleState::InEffect => "in_effect",
+            RuleState::CoolingDown => "cooling_down",
+            RuleState::Ran => "ran",
+            RuleState::DryRun => "dry_run",
+        }
+    }
+}
+
+struct RuleStatus {
+    state: RuleState,
+    /// Results of the conditions, in order.
+    conditions: Vec<bool>,
+    /// When the conditions started to hold.
+    matched_since: Option<SystemTime>,
+    last_run: Option<SystemTime>,
+    last_result: Option<String>,
+}
+
+impl Default for RuleStatus {
+    fn default() -> Self {
+        RuleStatus {
+            state: RuleState::Idle,
+            conditions: Vec::new(),
+            matched_since: None,
+            last_run: None,
+            last_result: None,
+        }
+    }
+}
+
+/// What runs changes on its own, rather than on behalf of an HTTP request.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Trigger {
+    Rule,
+    Schedule,
+}
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Trigger::Rule => write!(f, "rule"),
+            Trigger::Schedule => write!(f, "schedule"),
+        }
+    }
+}
+
+/// The changes of a rule or schedule that is due, to run with `dispatch`.
+pub struct Firing {
+    pub trigger: Trigger,
+    pub name: String,
+    /// The thermostat the changes are for, the account's first one if unset.
+    pub thermostat: Option<String>,
+    pub changes: Vec<ChangeThermostat>,
+}
+
+/// Reports how running a `Firing` went back to its backend, which passes it
+/// on to whatever triggered it.
+#[derive(Message)]
+pub struct FiringOutcome {
+    pub trigger: Trigger,
+    pub name: String,
+    pub result: ::std::result::Result<(), String>,
+}
+
+/// Evaluates an account's rules and keeps what the HTTP API reports about
+/// them. Owned by the backend actor, which feeds it every new state.
+pub struct RuleEngine {
+    rules: Vec<RuleConfig>,
+    units: Units,
+    /// By rule name, so reloads keep the state of unchanged rules.
+    statuses: HashMap<String, RuleStatus>,
+    evaluated_at: Option<SystemTime>,
+}
+
+impl RuleEngine {
+    pub fn new(config: &Config) -> Self {
+        let mut engine = RuleEngine {
+            rules: Vec::new(),
+            units: config.units,
+            statuses: HashMap::new(),
+            evaluated_at: None,
+        };
+        engine.reconfigure(config);
+        engine
+    }
+
+    pub fn reconfigure(&mut self, config: &Config) {
+        self.rules = config.rules.clone();
+        self.units = config.units;
+
+        let rules = &self.rules;
+        self.statuses
+            .retain(|name, _| rules.iter().any(|rule| rule.name == *name));
+    }
+
+    /// Checks every rule against its thermostat and returns the ones to run.
+    /// Dry runs are only logged.
+    pub fn evaluate(&mut self, thermostats: &[Thermostat]) -> Vec<Firing> {
+        let now = SystemTime::now();
+        let units = self.units;
+        let mut firings = Vec::new();
+        self.evaluated_at = Some(now);
+
+        for rule in &self.rules {
+            let status = self.statuses.entry(rule.name.clone()).or_default();
+            let thermostat = match Thermostat::select(thermostats, rule.thermostat.as_deref()) {
+                Some(thermostat) => thermostat,
+                None => {
+                    status.conditions.clear();
+                    status.matched_since = None;
+                    status.state = RuleState::Idle;
+                    if let Some(ref selector) = rule.thermostat {
+                        status.last_result = Some(format!("no thermostat `{}`", selector));
+                    }
+                    continue;
+                }
+            };
+            status.conditions = rule
+                .conditions
+                .iter()
+                .map(|condition| condition.holds(thermostat, units))
+                .collect();
+
+            if !status.conditions.iter().all(|&holds| holds) {
+                status.matched_since = None;
+                status.state = RuleState::Idle;
+                continue;
+            }
+
+            let since = *status.matched_since.get_or_insert(now);
+            let elapsed = |time: SystemTime| now.duration_since(time).unwrap_or_default();
+
+            status.state = if elapsed(since) < Duration::from_secs(rule.after) {
+                RuleState::Waiting
+            } else if rule
+                .actions
+                .iter()
+                .all(|action| action.in_effect(thermostat, units))
+            {
+                RuleState::InEffect
+            } else if status
+                .last_run
+                .is_some_and(|last| elapsed(last) < Duration::from_secs(rule.cooldown))
+            {
+                RuleState::CoolingDown
+            } else if rule.dry_run {
+                RuleState::DryRun
+            } else {
+                RuleState::Ran
+            };
+
+            let actions = || {
+                rule.actions
+                    .iter()
+                    .map(RuleAction::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", then ")
+            };
+            match status.state {
+                RuleState::DryRun => {
+                    info!("rule {} would {} (dry run)", rule.name, actions());
+                    status.last_run = Some(now);
+                    status.last_result = Some(format!("would {}", actions()));
+                }
+                RuleState::Ran => {
+                    status.last_run = Some(now);
+                    status.last_result = Some("running".to_owned());
+                    firings.push(Firing {
+                        trigger: Trigger::Rule,
+                        name: rule.name.clone(),
+                        thermostat: rule.thermostat.clone(),
+                        changes: rule
+                            .actions
+                            .iter()
+                            .map(|action| action.change(units))
+                            .collect(),
+                    });
+                }
+                _ => (),
+            }
+        }
+
+        firings
+    }
+
+    pub fn record_outcome(&mut self, outcome: FiringOutcome) {
+        if let Some(status) = self.statuses.get_mut(&outcome.name) {
+            status.last_result = Some(match outcome.result {
+                Ok(()) => "succeeded".to_owned(),
+                Err(e) => format!("failed: {}", e),
+            });
+        }
+    }
+
+    pub fn report(&self) -> RulesReport {
+        RulesReport {
+            evaluated_at: self.evaluated_at.map(unix_time),
+            rules: self
+                .rules
+                .iter()
+                .map(|rule| {
+                    let status = self.statuses.get(&rule.name);
+
+                    RuleReport {
+                        name: rule.name.clone(),
+                        thermostat: rule.thermostat.clone(),
+                        state: status.map_or("idle", |status| status.state.describe()),
+                        dry_run: rule.dry_run,
+                        conditions: status
+                            .map_or_else(Vec::new, |status| status.conditions.clone()),
+                        matched_since: status
+                            .and_then(|status| status.matched_since.map(unix_time)),
+                        last_run: status.and_then(|status| status.last_run.map(unix_time)),
+                        last_result: status.and_then(|status| status.last_result.clone()),
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Runs a firing's changes one after another through `addr`, then reports
+/// the outcome back to it.
+pub fn dispatch<A>(addr: Addr<A>, firing: Firing)
+where
+    A: Actor<Context = Context<A>> + Handler<Traced<TargetedChange>> + Handler<FiringOutcome>,
+{
+    let Firing {
+        trigger,
+        name,
+        thermostat,
+        changes,
+    } = firing;
+    let request_id = RequestId::generate();
+    info!("[{}] running {} {}", request_id, trigger, name);
+
+    let change_addr = addr.clone();
+    let run = stream::iter_ok(changes)
+        .for_each(move |change| {
+            change_addr
+                .send(Traced::new(
+                    request_id.clone(),
+                    TargetedChange {
+                        thermostat: thermostat.clone(),
+                        change,
+                    },
+                ))
+                .map_err(|_| err_msg("mailbox error"))
+                .and_then(|resp| resp)
+                .and_then(|fut| fut)
+                .map(|_| ())
+        })
+        .then(move |result| {
+            if let Err(ref e) = result {
+                warn!("{} {} failed: {}", trigger, name, e);
+            }
+            addr.do_send(FiringOutcome {
+                trigger,
+                name,
+                result: result.map_err(|e| e.to_string()),
+            });
+            Ok(())
+        });
+
+    Arbiter::spawn(run);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use thermostat::Settings;
+
+    fn engine(rules: &str) -> RuleEngine {
+        let config: Config = ::toml::from_str(rules).expect("config");
+        RuleEngine::new(&config)
+    }
+
+    fn thermostat(name: &str, hvac_mode: &str, fan_min_on_time: i32) -> Thermostat {
+        Thermostat {
+            identifier: format!("{}-id", name),
+            name: name.to_owned(),
+            settings: Settings {
+                hvac_mode: hvac_mode.to_owned(),
+                fan_min_on_time,
+                ..Settings::default()
+            },
+            ..Thermostat::default()
+        }
+    }
+
+    fn state(engine: &RuleEngine) -> &'static str {
+        engine.report().rules[0].state
+    }
+
+    const COOLING_FAN: &str = r#"
+        [[rules]]
+        name = "cooling-fan"
+        conditions = [{ check = "hvac_mode", mode = "cool" }]
+        actions = [{ action = "fan_min_on_time", minutes = 20 }]
+    "#;
+
+    #[test]
+    fn runs_once_then_cools_down() {
+        let mut engine = engine(COOLING_FAN);
+
+        assert!(engine.evaluate(&[thermostat("Main", "heat", 0)]).is_empty());
+        assert_eq!(state(&engine), "idle");
+
+        let firings = engine.evaluate(&[thermostat("Main", "cool", 0)]);
+        assert_eq!(firings.len(), 1);
+        assert_eq!(firings[0].name, "cooling-fan");
+        assert_eq!(firings[0].thermostat, None);
+        assert_eq!(state(&engine), "ran");
+
+        // The write has not shown up in a poll yet.
+        assert!(engine.evaluate(&[thermostat("Main", "cool", 0)]).is_empty());
+        assert_eq!(state(&engine), "cooling_down");
+
+        assert!(engine
+            .evaluate(&[thermostat("Main", "cool", 20)])
+            .is_empty());
+        assert_eq!(state(&engine), "in_effect");
+
+        assert!(engine
+            .evaluate(&[thermostat("Main", "heat", 20)])
+            .is_empty());
+        assert_eq!(state(&engine), "idle");
+        assert_eq!(engine.report().rules[0].matched_since, None);
+    }
+
+    #[test]
+    fn waits_until_the_conditions_held_long_enough() {
+        let mut engine = engine(&format!("{}after = 600", COOLING_FAN));
+
+        assert!(engine.evaluate(&[thermostat("Main", "cool", 0)]).is_empty());
+        assert_eq!(state(&engine), "waiting");
+        assert!(engine.report().rules[0].matched_since.is_some());
+    }
+
+    #[test]
+    fn dry_runs_only_report() {
+        let mut engine = engine(&format!("{}dry_run = true", COOLING_FAN));
+
+        assert!(engine.evaluate(&[thermostat("Main", "cool", 0)]).is_empty());
+        let report = engine.report();
+        assert_eq!(report.rules[0].state, "dry_run");
+        assert_eq!(
+            report.rules[0].last_result,
+            Some("would run the fan 20 minutes per hour".to_owned())
+        );
+    }
+
+    #[test]
+    fn rules_watch_their_own_thermostat() {
+        let mut engine = engine(&format!("{}thermostat = \"upstairs\"", COOLING_FAN));
+        let thermostats = [
+            thermostat("Main", "heat", 0),
+            thermostat("Upstairs", "cool", 0),
+        ];
+
+        let firings = engine.evaluate(&thermostats);
+        assert_eq!(firings.len(), 1);
+        assert_eq!(firings[0].thermostat, Some("upstairs".to_owned()));
+        assert_eq!(engine.report().rules[0].conditions, vec![true]);
+
+        assert!(engine.evaluate(&thermostats[..1]).is_empty());
+        let report = engine.report();
+        assert_eq!(report.rules[0].state, "idle");
+        assert_eq!(
+            report.rules[0].last_result,
+            Some("no thermostat `upstairs`".to_owned())
+        );
+    }
+}
@@ -0,0 +1,575 @@
+//! Local schedules from the `[[schedules]]` config tables, run at wall clock
+//! times in the thermostat's time zone independently of its ecobee program.
+//! When each schedule last ran is kept in `state_dir`, so runs missed while
+//! castform was down are made up after a restart.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+
+use chrono::{
+    DateTime, Datelike, Duration, FixedOffset, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc,
+    Weekday,
+};
+use chrono_tz::Tz;
+
+use config::{is_valid_name, Config};
+use response::{ScheduleReport, SchedulesReport};
+use rules::{self, Firing, FiringOutcome, RuleAction, Trigger};
+use state::{load_json_state, save_json_state};
+use temperature::Units;
+use thermostat::Thermostat;
+
+/// Seconds between checks for due schedules.
+pub const TICK: u64 = 30;
+
+const WEEKDAYS: &[Weekday] = &[
+    Weekday::Mon,
+    Weekday::Tue,
+    Weekday::Wed,
+    Weekday::Thu,
+    Weekday::Fri,
+];
+const WEEKENDS: &[Weekday] = &[Weekday::Sat, Weekday::Sun];
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct ScheduleConfig {
+    pub name: String,
+    /// Identifier or name of the thermostat the schedule changes and whose
+    /// time zone `at` is in, the account's first one if unset.
+    #[serde(default)]
+    pub thermostat: Option<String>,
+    /// `mon` to `sun`, `weekdays`, `weekends` or `daily`. Every day if empty.
+    #[serde(default)]
+    pub days: Vec<String>,
+    /// Local time of day as `HH:MM`.
+    pub at: String,
+    /// Run in order, stopping at the first one that fails.
+    pub actions: Vec<RuleAction>,
+    /// Seconds a run may be late and still happen, e.g. because castform was
+    /// down at the time.
+    #[serde(default = "default_catch_up")]
+    pub catch_up: u64,
+}
+
+fn default_catch_up() -> u64 {
+    60 * 60
+}
+
+/// Adds the problems with `schedules` to `problems`, for `Config::validate`.
+pub fn validate(schedules: &[ScheduleConfig], problems: &mut Vec<String>) {
+    let mut names = Vec::new();
+
+    for schedule in schedules {
+        let owner = format!("schedule `{}`", schedule.name);
+
+        if !is_valid_name(&schedule.name) {
+            problems.push(format!(
+                "schedule name `{}` must be non-empty and only use letters, digits, `-` and `_`",
+                schedule.name
+            ));
+        } else if names.contains(&&schedule.name) {
+            problems.push(format!("schedule name `{}` is used twice", schedule.name));
+        }
+        names.push(&schedule.name);
+
+        if schedule.thermostat.as_deref() == Some("") {
+            problems.push(format!("{} names an empty thermostat", owner));
+        }
+        if let Err(e) = parse_days(&schedule.days) {
+            problems.push(format!("{} {}", owner, e));
+        }
+        if let Err(e) = parse_at(&schedule.at) {
+            problems.push(format!("{} {}", owner, e));
+        }
+        rules::validate_actions(&owner, &schedule.actions, problems);
+    }
+}
+
+fn parse_days(days: &[String]) -> ::std::result::Result<Vec<Weekday>, String> {
+    if days.is_empty() {
+        return Ok(WEEKDAYS.iter().chain(WEEKENDS).cloned().collect());
+    }
+
+    let mut parsed = Vec::new();
+    for day in days {
+        match day.to_lowercase().as_str() {
+            "daily" => parsed.extend(WEEKDAYS.iter().chain(WEEKENDS)),
+            "weekdays" => parsed.extend(WEEKDAYS),
+            "weekends" => parsed.extend(WEEKENDS),
+            other => parsed.push(other.parse::<Weekday>().map_err(|_| {
+                format!(
+                    "has unknown day `{}`, expected mon to sun, weekdays, weekends or daily",
+                    day
+                )
+            })?),
+        }
+    }
+
+    Ok(parsed)
+}
+
+fn parse_at(at: &str) -> ::std::result::Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(at, "%H:%M")
+        .map_err(|_| format!("runs at `{}`, expected a time of day like 06:30", at))
+}
+
+/// The time zone schedules run in.
+enum Zone {
+    Named(Tz),
+    /// A fixed UTC offset, which misses daylight saving changes.
+    Fixed(FixedOffset),
+}
+
+impl Zone {
+    /// The thermostat's IANA `timeZone`, or its current UTC offset if ecobee
+    /// reports a name castform does not know.
+    fn of(thermostat: &Thermostat) -> Zone {
+        let location = thermostat.location.clone().unwrap_or_default();
+
+        match location.time_zone.parse::<Tz>() {
+            Ok(tz) => Zone::Named(tz),
+            Err(_) => Zone::Fixed(
+                FixedOffset::east_opt(location.time_zone_offset_minutes * 60)
+                    .unwrap_or_else(|| FixedOffset::east_opt(0).expect("zero offset")),
+            ),
+        }
+    }
+
+    fn name(&self) -> String {
+        match *self {
+            Zone::Named(tz) => tz.name().to_owned(),
+            Zone::Fixed(offset) => offset.to_string(),
+        }
+    }
+
+    fn local(&self, time: DateTime<Utc>) -> NaiveDateTime {
+        match *self {
+            Zone::Named(tz) => time.with_timezone(&tz).naive_local(),
+            Zone::Fixed(offset) => time.with_timezone(&offset).naive_local(),
+        }
+    }
+
+    /// The instant of a local time. Times skipped by a daylight saving
+    /// change happen an hour later, repeated ones the first time.
+    fn utc(&self, local: NaiveDateTime) -> Option<DateTime<Utc>> {
+        let resolve = |local: NaiveDateTime| match *self {
+            Zone::Named(tz) => tz
+                .from_local_datetime(&local)
+                .earliest()
+                .map(|time| time.with_timezone(&Utc)),
+            Zone::Fixed(offset) => offset
+                .from_local_datetime(&local)
+                .earliest()
+                .map(|time| time.with_timezone(&Utc)),
+        };
+
+        resolve(local).or_else(|| resolve(local + Duration::hours(1)))
+    }
+
+    fn format(&self, time: DateTime<Utc>) -> String {
+        match *self {
+            Zone::Named(tz) => time.with_timezone(&tz).to_rfc3339(),
+            Zone::Fixed(offset) => time.with_timezone(&offset).to_rfc3339(),
+        }
+    }
+}
+
+struct Schedule {
+    config: ScheduleConfig,
+    days: Vec<Weekday>,
+    at: NaiveTime,
+}
+
+impl Schedule {
+    /// `None` for configs `validate` rejects.
+    fn parse(config: &ScheduleConfig) -> Option<Schedule> {
+        Some(Schedule {
+            days: parse_days(&config.days).ok()?,
+            at: parse_at(&config.at).ok()?,
+            config: config.clone(),
+        })
+    }
+
+    fn thermostat<'a>(&self, thermostats: &'a [Thermostat]) -> Option<&'a Thermostat> {
+        Thermostat::select(thermostats, self.config.thermostat.as_deref())
+    }
+
+    /// When the schedule runs on `date`, if it does.
+    fn run_on(&self, zone: &Zone, date: NaiveDate) -> Option<DateTime<Utc>> {
+        if self.days.contains(&date.weekday()) {
+            zone.utc(date.and_time(self.at))
+        } else {
+            None
+        }
+    }
+
+    /// The latest run after `after` and at or before `until`.
+    fn latest(
+        &self,
+        zone: &Zone,
+        after: DateTime<Utc>,
+        until: DateTime<Utc>,
+    ) -> Option<DateTime<Utc>> {
+        // A day either side covers runs shifted by daylight saving changes.
+        let first = zone.local(after).date() - Duration::days(1);
+        let mut date = zone.local(until).date() + Duration::days(1);
+
+        while date >= first {
+            match self.run_on(zone, date) {
+                Some(run) if run > after && run <= until => return Some(run),
+                _ => date -= Duration::days(1),
+            }
+        }
+
+        None
+    }
+
+    /// The first run after `after`.
+    fn next(&self, zone: &Zone, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let start = zone.local(after).date();
+
+        (0..9)
+            .filter_map(|day| self.run_on(zone, start + Duration::days(day)))
+            .find(|&run| run > after)
+    }
+}
+
+/// Runs an account's schedules and keeps what the HTTP API reports about
+/// them. Owned by the backend actor, which calls `tick` every `TICK`
+/// seconds once it knows the thermostat.
+pub struct Scheduler {
+    account: String,
+    schedules: Vec<Schedule>,
+    units: Units,
+    state_path: Option<PathBuf>,
+    /// Unix time of the latest run of each schedule, by name.
+    last_runs: BTreeMap<String, i64>,
+    /// Runs before this are only made up if `last_runs` says they were
+    /// missed, so new schedules don't fire for times before castform started.
+    started: DateTime<Utc>,
+    last_results: HashMap<String, String>,
+}
+
+impl Scheduler {
+    pub fn new(config: &Config, account: &str) -> Self {
+        let state_path = config
+            .state_dir
+            .as_ref()
+            .map(|dir| PathBuf::from(dir).join(format!("{}-schedules.json", account)));
+        let last_runs = state_path
+            .as_ref()
+            .map(|path| load_json_state(path, "schedule state"))
+            .unwrap_or_default();
+
+        let mut scheduler = Scheduler {
+            account: account.to_owned(),
+            schedules: Vec::new(),
+            units: config.units,
+            state_path,
+            last_runs,
+            started: Utc::now(),
+            last_results: HashMap::new(),
+        };
+        scheduler.reconfigure(config);
+        scheduler
+    }
+
+    pub fn reconfigure(&mut self, config: &Config) {
+        self.schedules = config
+            .schedules
+            .iter()
+            .filter_map(Schedule::parse)
+            .collect();
+        self.units = config.units;
+
+        let schedules = &self.schedules;
+        self.last_results.retain(|name, _| {
+            schedules
+                .iter()
+                .any(|schedule| schedule.config.name == *name)
+        });
+    }
+
+    /// Returns the schedules due since the last tick, oldest run first, and
+    /// records that they ran. Schedules whose thermostat isn't among
+    /// `thermostats` wait for it.
+    pub fn tick(&mut self, thermostats: &[Thermostat]) -> Vec<Firing> {
+        self.due(thermostats, Utc::now())
+    }
+
+    fn due(&mut self, thermostats: &[Thermostat], now: DateTime<Utc>) -> Vec<Firing> {
+        let mut due = Vec::new();
+
+        for schedule in &self.schedules {
+            let name = &schedule.config.name;
+            let zone = match schedule.thermostat(thermostats) {
+                Some(thermostat) => Zone::of(thermostat),
+                None => continue,
+            };
+            let after = self
+                .last_runs
+                .get(name)
+                .and_then(|&last| Utc.timestamp_opt(last, 0).single())
+                .unwrap_or(self.started)
+                .max(now - Duration::seconds(schedule.config.catch_up as i64));
+
+            if let Some(run) = schedule.latest(&zone, after, now) {
+                if now - run > Duration::seconds(2 * TICK as i64) {
+                    info!(
+                        "making up the run of schedule {} due at {}",
+                        name,
+                        zone.format(run)
+                    );
+                }
+
+                due.push((
+                    run,
+                    Firing {
+                        trigger: Trigger::Schedule,
+                        name: name.clone(),
+                        thermostat: schedule.config.thermostat.clone(),
+                        changes: schedule
+                            .config
+                            .actions
+                            .iter()
+                            .map(|action| action.change(self.units))
+                            .collect(),
+                    },
+                ));
+            }
+        }
+
+        if due.is_empty() {
+            return Vec::new();
+        }
+
+        due.sort_by_key(|&(run, _)| run);
+        for &(run, ref firing) in &due {
+            self.last_runs.insert(firing.name.clone(), run.timestamp());
+            self.last_results
+                .insert(firing.name.clone(), "running".to_owned());
+        }
+        self.save();
+
+        due.into_iter().map(|(_, firing)| firing).collect()
+    }
+
+    pub fn record_outcome(&mut self, outcome: FiringOutcome) {
+        self.last_results.insert(
+            outcome.name,
+            match outcome.result {
+                Ok(()) => "succeeded".to_owned(),
+                Err(e) => format!("failed: {}", e),
+            },
+        );
+    }
+
+    /// Without its thermostat a schedule's time zone is unknown, so are its
+    /// next runs.
+    pub fn report(&self, thermostats: &[Thermostat]) -> SchedulesReport {
+        let now = Utc::now();
+
+        SchedulesReport {
+            time_zone: thermostats
+                .first()
+                .map(|thermostat| Zone::of(thermostat).name()),
+            schedules: self
+                .schedules
+                .iter()
+                .map(|schedule| {
+                    let name = &schedule.config.name;
+                    let zone = schedule.thermostat(thermostats).map(Zone::of);
+                    let next = zone
+                        .as_ref()
+                        .and_then(|zone| schedule.next(zone, now).map(|run| (zone, run)));
+
+                    ScheduleReport {
+                        name: name.clone(),
+                        thermostat: schedule.config.thermostat.clone(),
+                        time_zone: zone.as_ref().map(Zone::name),
+                        days: schedule
+                            .days
+                            .iter()
+                            .map(|day| day.to_string().to_lowercase())
+                            .collect(),
+                        at: schedule.config.at.clone(),
+                        actions: schedule
+                            .config
+                            .actions
+                            .iter()
+                            .map(RuleAction::to_string)
+                            .collect(),
+                        next_run: next.map(|(_, run)| run.timestamp() as u64),
+                        next_run_local: next.map(|(zone, run)| zone.format(run)),
+                        last_run: self.last_runs.get(name).map(|&last| last as u64),
+                        last_result: self.last_results.get(name).cloned(),
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    /// Failures are logged, at worst runs missed during a restart are lost.
+    fn save(&self) {
+        let path = match self.state_path {
+            Some(ref path) => path,
+            None => return,
+        };
+
+        if let Err(e) = save_json_state(path, &self.last_runs) {
+            warn!(
+                "failed to save the schedules of {} to {}: {}",
+                self.account,
+                path.display(),
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use thermostat::Location;
+
+    fn scheduler(schedules: &str, started: &str) -> Scheduler {
+        let config: Config = ::toml::from_str(schedules).expect("config");
+        let mut scheduler = Scheduler::new(&config, "home");
+        scheduler.started = utc(started);
+        scheduler
+    }
+
+    fn thermostat(name: &str, time_zone: &str) -> Thermostat {
+        Thermostat {
+            identifier: format!("{}-id", name),
+            name: name.to_owned(),
+            location: Some(Location {
+                time_zone: time_zone.to_owned(),
+                ..Location::default()
+            }),
+            ..Thermostat::default()
+        }
+    }
+
+    fn toronto() -> Vec<Thermostat> {
+        vec![thermostat("Main", "America/Toronto")]
+    }
+
+    fn utc(time: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(time)
+            .expect("time")
+            .with_timezone(&Utc)
+    }
+
+    fn names(firings: &[Firing]) -> Vec<&str> {
+        firings.iter().map(|firing| firing.name.as_str()).collect()
+    }
+
+    const NIGHTLY: &str = r#"
+        [[schedules]]
+        name = "nightly"
+        at = "02:30"
+        actions = [{ action = "resume_program" }]
+    "#;
+
+    #[test]
+    fn runs_skipped_by_spring_forward_happen_an_hour_later() {
+        // 02:30 doesn't exist on 2021-03-14 in Toronto, clocks jump from
+        // 02:00 EST to 03:00 EDT.
+        let mut scheduler = scheduler(NIGHTLY, "2021-03-14T01:00:00-05:00");
+        let thermostats = toronto();
+
+        assert!(scheduler
+            .due(&thermostats, utc("2021-03-14T03:29:00-04:00"))
+            .is_empty());
+        assert_eq!(
+            names(&scheduler.due(&thermostats, utc("2021-03-14T03:30:00-04:00"))),
+            vec!["nightly"]
+        );
+        assert!(scheduler
+            .due(&thermostats, utc("2021-03-14T03:31:00-04:00"))
+            .is_empty());
+    }
+
+    #[test]
+    fn repeated_times_run_once_on_fall_back() {
+        // 01:30 happens twice on 2021-11-07 in Toronto, first in EDT, then in
+        // EST.
+        let schedules = NIGHTLY.replace("02:30", "01:30");
+        let mut scheduler = scheduler(&schedules, "2021-11-07T00:00:00-04:00");
+        let thermostats = toronto();
+
+        assert_eq!(
+            names(&scheduler.due(&thermostats, utc("2021-11-07T01:30:00-04:00"))),
+            vec!["nightly"]
+        );
+        assert!(scheduler
+            .due(&thermostats, utc("2021-11-07T01:30:00-05:00"))
+            .is_empty());
+        assert!(scheduler
+            .due(&thermostats, utc("2021-11-07T01:31:00-05:00"))
+            .is_empty());
+    }
+
+    #[test]
+    fn runs_missed_while_down_are_made_up_within_catch_up() {
+        let thermostats = toronto();
+        let restart = |now: &str| {
+            let mut scheduler = scheduler(NIGHTLY, now);
+            scheduler.last_runs.insert(
+                "nightly".to_owned(),
+                utc("2021-06-01T02:30:00-04:00").timestamp(),
+            );
+            scheduler.due(&thermostats, utc(now))
+        };
+
+        assert_eq!(
+            names(&restart("2021-06-02T03:00:00-04:00")),
+            vec!["nightly"]
+        );
+        // More than the default hour late.
+        assert!(restart("2021-06-02T04:00:00-04:00").is_empty());
+    }
+
+    #[test]
+    fn new_schedules_do_not_run_for_times_before_startup() {
+        let mut scheduler = scheduler(NIGHTLY, "2021-06-02T02:45:00-04:00");
+        let thermostats = toronto();
+
+        assert!(scheduler
+            .due(&thermostats, utc("2021-06-02T02:45:30-04:00"))
+            .is_empty());
+        assert_eq!(
+            names(&scheduler.due(&thermostats, utc("2021-06-03T02:30:00-04:00"))),
+            vec!["nightly"]
+        );
+    }
+
+    #[test]
+    fn schedules_run_in_their_thermostat_time_zone() {
+        let schedules = format!("{}thermostat = \"cabin\"", NIGHTLY);
+        let mut scheduler = scheduler(&schedules, "2021-06-02T00:00:00-04:00");
+        let thermostats = vec![
+            thermostat("Main", "America/Toronto"),
+            thermostat("Cabin", "America/Vancouver"),
+        ];
+
+        assert!(scheduler
+            .due(&thermostats, utc("2021-06-02T02:30:00-04:00"))
+            .is_empty());
+        let firings = scheduler.due(&thermostats, utc("2021-06-02T02:30:00-07:00"));
+        assert_eq!(names(&firings), vec!["nightly"]);
+        assert_eq!(firings[0].thermostat, Some("cabin".to_owned()));
+
+        // Without its thermostat the schedule waits.
+        assert!(scheduler
+            .due(&thermostats[..1], utc("2021-06-03T02:30:00-07:00"))
+            .is_empty());
+        let report = scheduler.report(&thermostats);
+        assert_eq!(report.time_zone, Some("America/Toronto".to_owned()));
+        assert_eq!(
+            report.schedules[0].time_zone,
+            Some("America/Vancouver".to_owned())
+        );
+    }
+}
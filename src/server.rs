@@ -1,17 +1,190 @@
+use std::sync::{Arc, RwLock};
+
 use actix::Addr;
 use actix_web::http::StatusCode;
 use actix_web::server::{HttpHandler, HttpHandlerTask};
-use actix_web::{http, middleware, App, Error, Form, HttpResponse, Json, State};
-use failure::err_msg;
-use futures::Future;
+use actix_web::{
+    http, middleware, App, Error, FromRequest, HttpMessage, HttpRequest, HttpResponse, Json, Path,
+    State,
+};
+use futures::{future, Future};
+use serde::de::DeserializeOwned;
+use serde_urlencoded;
+
+use auth::Authenticate;
+use backend::{BackendAddr, ChangeThermostat, HoldType, RefreshNow};
+use config::{AuthConfig, Config};
+use error::ApiError;
+use presence::{Household, PresenceEvent, PresenceQuery, ReportPresence};
+use query::{HealthQuery, RawQuery, RulesQuery, SchedulesQuery, StatusQuery, ThermostatsQuery};
+use request_id::{AssignRequestId, RequestId, Traced};
+use response::{
+    EcobeeStatus, PresenceReport, Readiness, ReadinessReport, RulesReport, SchedulesReport,
+    ThermostatStatus, ThermostatSummary,
+};
+use temperature::{Temperature, Units};
+use thermostat::Thermostat;
+
+/// actix-web's default access log format, prefixed with the request ID.
+const REQUEST_LOG_FORMAT: &str =
+    r#"[%{x-request-id}o] %a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T"#;
+
+/// Settings read on every request rather than baked into the app, so a config
+/// reload reaches all workers without restarting the server.
+pub struct ServerSettings {
+    pub units: Units,
+    pub ready_max_age: u64,
+    pub auth: AuthConfig,
+}
+
+impl ServerSettings {
+    pub fn from_config(config: &Config) -> Self {
+        ServerSettings {
+            units: config.units,
+            ready_max_age: config.ready_max_age,
+            auth: config.auth.clone(),
+        }
+    }
+}
 
-use ecobee::{ChangeThermostat, EcobeeActor};
-use query::EcobeeQuery;
-use response::{EcobeeResponse, EcobeeStatus};
+pub type SharedSettings = Arc<RwLock<ServerSettings>>;
+
+/// A running backend and the account name it is routed by.
+#[derive(Clone)]
+pub struct Account {
+    pub name: String,
+    pub backend: BackendAddr,
+    /// Who of the account's household is home.
+    pub presence: Addr<Household>,
+}
 
 #[derive(Clone)]
 struct HttpServerState {
-    ecobee: Addr<EcobeeActor>,
+    /// Never empty. The first account also serves the unprefixed routes.
+    accounts: Vec<Account>,
+    settings: SharedSettings,
+}
+
+/// Extracts the backend of the account named by the `{account}` path
+/// segment, or of the first account on routes without one.
+struct AccountBackend(BackendAddr);
+
+impl FromRequest<HttpServerState> for AccountBackend {
+    type Config = ();
+    type Result = Result<Self, Error>;
+
+    fn from_request(req: &HttpRequest<HttpServerState>, _: &Self::Config) -> Self::Result {
+        Ok(AccountBackend(routed_account(req)?.backend.clone()))
+    }
+}
+
+/// Extracts the household of the account routed to, like `AccountBackend`.
+struct AccountHousehold(Addr<Household>);
+
+impl FromRequest<HttpServerState> for AccountHousehold {
+    type Config = ();
+    type Result = Result<Self, Error>;
+
+    fn from_request(req: &HttpRequest<HttpServerState>, _: &Self::Config) -> Self::Result {
+        Ok(AccountHousehold(routed_account(req)?.presence.clone()))
+    }
+}
+
+fn routed_account(req: &HttpRequest<HttpServerState>) -> Result<&Account, ApiError> {
+    let accounts = &req.state().accounts;
+    match req.match_info().get("account") {
+        Some(name) => accounts
+            .iter()
+            .find(|account| account.name == name)
+            .ok_or_else(|| ApiError::UnknownAccount(name.to_owned())),
+        None => Ok(&accounts[0]),
+    }
+}
+
+/// Request body extractor accepting either `application/json` or a
+/// urlencoded form, chosen by the request's content type.
+struct Payload<T>(T);
+
+impl<T, S> FromRequest<S> for Payload<T>
+where
+    T: DeserializeOwned + 'static,
+    S: 'static,
+{
+    type Config = ();
+    type Result = Box<Future<Item = Self, Error = Error>>;
+
+    fn from_request(req: &HttpRequest<S>, _: &Self::Config) -> Self::Result {
+        if req.content_type() == "application/json" {
+            Box::new(
+                req.json()
+                    .map(Payload)
+                    .map_err(|e| ApiError::Validation(e.to_string()).into()),
+            )
+        } else {
+            Box::new(
+                req.urlencoded()
+                    .map(Payload)
+                    .map_err(|e| ApiError::Validation(e.to_string()).into()),
+            )
+        }
+    }
+}
+
+/// Query string extractor reporting malformed parameters as validation errors.
+struct Params<T>(T);
+
+impl<T, S> FromRequest<S> for Params<T>
+where
+    T: DeserializeOwned,
+{
+    type Config = ();
+    type Result = Result<Self, Error>;
+
+    fn from_request(req: &HttpRequest<S>, _: &Self::Config) -> Self::Result {
+        serde_urlencoded::from_str(req.query_string())
+            .map(Params)
+            .map_err(|e| ApiError::Validation(e.to_string()).into())
+    }
+}
+
+#[derive(Deserialize)]
+struct UnitsParams {
+    units: Option<Units>,
+}
+
+#[derive(Deserialize)]
+struct StatusParams {
+    units: Option<Units>,
+    /// Identifier or name of the thermostat, the first one by default.
+    thermostat: Option<String>,
+    /// Maximum acceptable age of the cached state in seconds. Older state is
+    /// refreshed from ecobee before answering.
+    max_age: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct RefreshParams {
+    units: Option<Units>,
+    /// Identifier or name of the thermostat to report, the first one by
+    /// default.
+    thermostat: Option<String>,
+}
+
+impl HttpServerState {
+    fn units(&self, units: Option<Units>) -> Units {
+        units.unwrap_or_else(|| self.settings.read().expect("settings lock").units)
+    }
+}
+
+#[derive(Deserialize)]
+struct ThermostatPath {
+    /// Identifier or name of the thermostat.
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct PersonPath {
+    person: String,
 }
 
 #[derive(Deserialize)]
@@ -24,79 +197,384 @@ struct ModeForm {
     state: u8,
 }
 
-fn status(state: State<HttpServerState>) -> impl Future<Item = Json<EcobeeStatus>, Error = Error> {
-    state
-        .ecobee
-        .send(EcobeeQuery::Status)
-        .map_err(|_| err_msg("mailbox error"))
-        .flatten()
-        .map(|resp: EcobeeResponse| match resp {
-            EcobeeResponse::Status(status) => Json(status),
-        })
+fn query_status(
+    backend: &BackendAddr,
+    thermostat: Option<String>,
+) -> impl Future<Item = ThermostatStatus, Error = ApiError> {
+    backend
+        .send(StatusQuery(thermostat))
+        .map_err(|_| ApiError::Mailbox)
+        .and_then(|resp| resp.map_err(ApiError::from))
+}
+
+fn status(
+    (state, AccountBackend(backend), request_id, Params(params)): (
+        State<HttpServerState>,
+        AccountBackend,
+        RequestId,
+        Params<StatusParams>,
+    ),
+) -> impl Future<Item = Json<EcobeeStatus>, Error = Error> {
+    let StatusParams {
+        units,
+        thermostat,
+        max_age,
+    } = params;
+    let units = state.units(units);
+
+    query_status(&backend, thermostat.clone())
+        .and_then(
+            move |status| -> Box<Future<Item = ThermostatStatus, Error = ApiError>> {
+                match max_age {
+                    Some(max_age) if status.age() > max_age => Box::new(
+                        backend
+                            .send(Traced::new(request_id, RefreshNow))
+                            .map_err(|_| ApiError::Mailbox)
+                            .and_then(|resp| resp.map_err(ApiError::from))
+                            .and_then(move |fut| {
+                                fut.map_err(move |e| {
+                                    ApiError::Stale(format!(
+                                        "cache is older than {}s and refreshing failed: {}",
+                                        max_age, e
+                                    ))
+                                })
+                            })
+                            .and_then(move |_| query_status(&backend, thermostat)),
+                    ),
+                    _ => Box::new(future::ok(status)),
+                }
+            },
+        )
+        .map(move |status| Json(status.in_units(units)))
         .from_err()
 }
 
 fn set_heating_cooling_state(
-    (state, mode): (State<HttpServerState>, Form<ModeForm>),
-) -> impl Future<Item = HttpResponse, Error = Error> {
-    state
-        .ecobee
-        .send(ChangeThermostat::HvacMode(mode.state))
-        .map_err(|_| err_msg("mailbox error"))
-        .flatten()
-        .flatten()
-        .map(|_: ()| {
-            HttpResponse::build(StatusCode::OK)
-                .content_type("text/html; charset=utf-8")
-                .body("done")
-        })
-        .map_err(|e| {
-            eprintln!("error: {:?}", e);
+    (state, AccountBackend(backend), request_id, Params(params), Payload(mode)): (
+        State<HttpServerState>,
+        AccountBackend,
+        RequestId,
+        Params<UnitsParams>,
+        Payload<ModeForm>,
+    ),
+) -> impl Future<Item = Json<EcobeeStatus>, Error = Error> {
+    let units = state.units(params.units);
+
+    backend
+        .send(Traced::new(
+            request_id.clone(),
+            ChangeThermostat::HvacMode(mode.state),
+        ))
+        .map_err(|_| ApiError::Mailbox)
+        .and_then(|resp| resp.map_err(ApiError::from))
+        .and_then(|fut| fut.map_err(ApiError::from))
+        .map(move |status| Json(status.in_units(units)))
+        .map_err(move |e| {
+            error!("[{}] failed to change thermostat: {}", request_id, e);
             e
         })
         .from_err()
 }
 
 fn set_target_temperature(
-    (state, form): (State<HttpServerState>, Form<TemperatureForm>),
-) -> impl Future<Item = HttpResponse, Error = Error> {
-    state
-        .ecobee
-        .send(ChangeThermostat::Temperature(form.temperature))
-        .map_err(|_| err_msg("mailbox error"))
-        .flatten()
-        .flatten()
-        .map(|_: ()| {
-            HttpResponse::build(StatusCode::OK)
-                .content_type("text/html; charset=utf-8")
-                .body("done")
+    (state, AccountBackend(backend), request_id, Params(params), Payload(form)): (
+        State<HttpServerState>,
+        AccountBackend,
+        RequestId,
+        Params<UnitsParams>,
+        Payload<TemperatureForm>,
+    ),
+) -> impl Future<Item = Json<EcobeeStatus>, Error = Error> {
+    let units = state.units(params.units);
+    let change_id = request_id.clone();
+
+    future::result(Temperature::from_input(form.temperature, units))
+        .map_err(ApiError::from)
+        .and_then(move |temperature| {
+            backend
+                .send(Traced::new(
+                    change_id,
+                    ChangeThermostat::Temperature(temperature, HoldType::Indefinite),
+                ))
+                .map_err(|_| ApiError::Mailbox)
         })
-        .map_err(|e| {
-            eprintln!("error: {:?}", e);
+        .and_then(|resp| resp.map_err(ApiError::from))
+        .and_then(|fut| fut.map_err(ApiError::from))
+        .map(move |status| Json(status.in_units(units)))
+        .map_err(move |e| {
+            error!("[{}] failed to change thermostat: {}", request_id, e);
             e
         })
         .from_err()
 }
 
+fn resume_program(
+    (state, AccountBackend(backend), request_id, Params(params)): (
+        State<HttpServerState>,
+        AccountBackend,
+        RequestId,
+        Params<UnitsParams>,
+    ),
+) -> impl Future<Item = Json<EcobeeStatus>, Error = Error> {
+    let units = state.units(params.units);
+
+    backend
+        .send(Traced::new(
+            request_id.clone(),
+            ChangeThermostat::ResumeProgram,
+        ))
+        .map_err(|_| ApiError::Mailbox)
+        .and_then(|resp| resp.map_err(ApiError::from))
+        .and_then(|fut| fut.map_err(ApiError::from))
+        .map(move |status| Json(status.in_units(units)))
+        .map_err(move |e| {
+            error!("[{}] failed to resume the program: {}", request_id, e);
+            e
+        })
+        .from_err()
+}
+
+/// Fetches the account's thermostats from ecobee right away and answers with
+/// the fresh status. Requests arriving during a fetch share it.
+fn refresh(
+    (state, AccountBackend(backend), request_id, Params(params)): (
+        State<HttpServerState>,
+        AccountBackend,
+        RequestId,
+        Params<RefreshParams>,
+    ),
+) -> impl Future<Item = Json<EcobeeStatus>, Error = Error> {
+    let units = state.units(params.units);
+    let thermostat = params.thermostat;
+
+    backend
+        .send(Traced::new(request_id.clone(), RefreshNow))
+        .map_err(|_| ApiError::Mailbox)
+        .and_then(|resp| resp.map_err(ApiError::from))
+        .and_then(|fut| fut.map_err(ApiError::from))
+        .and_then(move |_| query_status(&backend, thermostat))
+        .map(move |status| Json(status.in_units(units)))
+        .map_err(move |e| {
+            error!("[{}] failed to refresh: {}", request_id, e);
+            e
+        })
+        .from_err()
+}
+
+fn thermostats(
+    AccountBackend(backend): AccountBackend,
+) -> impl Future<Item = Json<Vec<ThermostatSummary>>, Error = Error> {
+    backend
+        .send(ThermostatsQuery)
+        .map_err(|_| ApiError::Mailbox)
+        .and_then(|resp| resp.map_err(ApiError::from))
+        .map(Json)
+        .from_err()
+}
+
+/// Everything ecobee reported about one thermostat, as typed by castform.
+fn raw_thermostat(
+    (AccountBackend(backend), path): (AccountBackend, Path<ThermostatPath>),
+) -> impl Future<Item = Json<Thermostat>, Error = Error> {
+    backend
+        .send(RawQuery(path.into_inner().id))
+        .map_err(|_| ApiError::Mailbox)
+        .and_then(|resp| resp.map_err(ApiError::from))
+        .map(Json)
+        .from_err()
+}
+
+/// How the account's rules fared the last time they were evaluated.
+fn rules(
+    AccountBackend(backend): AccountBackend,
+) -> impl Future<Item = Json<RulesReport>, Error = Error> {
+    backend
+        .send(RulesQuery)
+        .map(Json)
+        .map_err(|_| ApiError::Mailbox)
+        .from_err()
+}
+
+/// The account's schedules with their next runs.
+fn schedules(
+    AccountBackend(backend): AccountBackend,
+) -> impl Future<Item = Json<SchedulesReport>, Error = Error> {
+    backend
+        .send(SchedulesQuery)
+        .map(Json)
+        .map_err(|_| ApiError::Mailbox)
+        .from_err()
+}
+
+fn report_presence(
+    household: &Addr<Household>,
+    request_id: RequestId,
+    person: String,
+    event: PresenceEvent,
+) -> impl Future<Item = Json<PresenceReport>, Error = Error> {
+    household
+        .send(Traced::new(
+            request_id.clone(),
+            ReportPresence { person, event },
+        ))
+        .map_err(|_| ApiError::Mailbox)
+        .and_then(|resp| resp.map_err(ApiError::from))
+        .map(Json)
+        .map_err(move |e| {
+            error!("[{}] failed to report presence: {}", request_id, e);
+            e
+        })
+        .from_err()
+}
+
+/// Marks a person as home, resuming the program if castform held the
+/// thermostat away.
+fn arrive(
+    (AccountHousehold(household), request_id, path): (
+        AccountHousehold,
+        RequestId,
+        Path<PersonPath>,
+    ),
+) -> impl Future<Item = Json<PresenceReport>, Error = Error> {
+    report_presence(
+        &household,
+        request_id,
+        path.into_inner().person,
+        PresenceEvent::Arrive,
+    )
+}
+
+/// Marks a person as away. The last one leaving starts the grace period
+/// before the away hold.
+fn leave(
+    (AccountHousehold(household), request_id, path): (
+        AccountHousehold,
+        RequestId,
+        Path<PersonPath>,
+    ),
+) -> impl Future<Item = Json<PresenceReport>, Error = Error> {
+    report_presence(
+        &household,
+        request_id,
+        path.into_inner().person,
+        PresenceEvent::Leave,
+    )
+}
+
+/// Who is home and whether castform holds the thermostat away.
+fn presence(
+    AccountHousehold(household): AccountHousehold,
+) -> impl Future<Item = Json<PresenceReport>, Error = Error> {
+    household
+        .send(PresenceQuery)
+        .map(Json)
+        .map_err(|_| ApiError::Mailbox)
+        .from_err()
+}
+
+fn healthz(_: &HttpRequest<HttpServerState>) -> HttpResponse {
+    HttpResponse::Ok().json(json!({ "status": "ok" }))
+}
+
+/// Ready once castform holds an ecobee token and has polled successfully
+/// within `ready_max_age` seconds, for every account.
+fn readyz(state: State<HttpServerState>) -> impl Future<Item = HttpResponse, Error = Error> {
+    let max_age = state.settings.read().expect("settings lock").ready_max_age;
+    let checks = state.accounts.iter().map(|account| {
+        let name = account.name.clone();
+
+        account
+            .backend
+            .send(HealthQuery)
+            .map_err(|_| ApiError::Mailbox)
+            .map(move |health| {
+                let ready =
+                    health.authenticated && health.last_poll_age.is_some_and(|age| age <= max_age);
+
+                (name, Readiness { ready, health })
+            })
+    });
+
+    future::join_all(checks.collect::<Vec<_>>())
+        .map(|accounts| {
+            let ready = accounts.iter().all(|(_, readiness)| readiness.ready);
+            let status = if ready {
+                StatusCode::OK
+            } else {
+                StatusCode::SERVICE_UNAVAILABLE
+            };
+
+            HttpResponse::build(status).json(ReadinessReport {
+                ready,
+                accounts: accounts.into_iter().collect(),
+            })
+        })
+        .from_err()
+}
+
+/// Registers the thermostat endpoints below `prefix`.
+fn thermostat_routes(app: App<HttpServerState>, prefix: &str) -> App<HttpServerState> {
+    app.resource(&format!("{}/status", prefix), |r| {
+        r.method(http::Method::GET).with_async(status)
+    })
+    .resource(&format!("{}/targetHeatingCoolingState", prefix), |r| {
+        r.method(http::Method::POST)
+            .with_async(set_heating_cooling_state)
+    })
+    .resource(&format!("{}/targetTemperature", prefix), |r| {
+        r.method(http::Method::POST)
+            .with_async(set_target_temperature)
+    })
+    .resource(&format!("{}/resume", prefix), |r| {
+        r.method(http::Method::POST).with_async(resume_program)
+    })
+    .resource(&format!("{}/refresh", prefix), |r| {
+        r.method(http::Method::POST).with_async(refresh)
+    })
+    .resource(&format!("{}/thermostats", prefix), |r| {
+        r.method(http::Method::GET).with_async(thermostats)
+    })
+    .resource(&format!("{}/thermostats/{{id}}/raw", prefix), |r| {
+        r.method(http::Method::GET).with_async(raw_thermostat)
+    })
+    .resource(&format!("{}/rules", prefix), |r| {
+        r.method(http::Method::GET).with_async(rules)
+    })
+    .resource(&format!("{}/schedules", prefix), |r| {
+        r.method(http::Method::GET).with_async(schedules)
+    })
+    .resource(&format!("{}/presence", prefix), |r| {
+        r.method(http::Method::GET).with_async(presence)
+    })
+    .resource(&format!("{}/presence/{{person}}/arrive", prefix), |r| {
+        r.method(http::Method::POST).with_async(arrive)
+    })
+    .resource(&format!("{}/presence/{{person}}/leave", prefix), |r| {
+        r.method(http::Method::POST).with_async(leave)
+    })
+}
+
+/// Serves every account below `/accounts/{account}`, and the first one
+/// configured on the unprefixed routes as well.
 pub fn build_server_factory(
-    ecobee: Addr<EcobeeActor>,
+    accounts: Vec<Account>,
+    settings: SharedSettings,
 ) -> impl IntoIterator<Item = Box<HttpHandler<Task = Box<HttpHandlerTask + 'static>> + 'static>> + 'static
 {
-    let state = HttpServerState { ecobee };
-    vec![
-        App::with_state(state)
-            .middleware(middleware::Logger::default())
-            .resource("/status", |r| {
-                r.method(http::Method::GET).with_async(status)
-            })
-            .resource("/targetHeatingCoolingState", |r| {
-                r.method(http::Method::POST)
-                    .with_async(set_heating_cooling_state)
-            })
-            .resource("/targetTemperature", |r| {
-                r.method(http::Method::POST)
-                    .with_async(set_target_temperature)
-            })
-            .boxed(),
-    ]
+    let state = HttpServerState {
+        accounts,
+        settings: settings.clone(),
+    };
+    let app = App::with_state(state)
+        .middleware(AssignRequestId)
+        .middleware(middleware::Logger::new(REQUEST_LOG_FORMAT))
+        .middleware(Authenticate::new(settings))
+        .resource("/healthz", |r| r.method(http::Method::GET).f(healthz))
+        .resource("/readyz", |r| {
+            r.method(http::Method::GET).with_async(readyz)
+        });
+    let app = thermostat_routes(app, "");
+    let app = thermostat_routes(app, "/accounts/{account}");
+
+    vec![app.boxed()]
 }
@@ -0,0 +1,463 @@
+use std::time::{Duration, SystemTime};
+
+use actix::{Actor, Addr, AsyncContext, Context, Handler, MessageResult};
+use failure::Error;
+use futures::{future, Future};
+
+use backend::{
+    Backend, BackendAddr, ChangeThermostat, Drain, Reconfigure, RefreshNow, TargetedChange,
+    MAX_FAN_MIN_ON_TIME,
+};
+use config::{AccountConfig, Config};
+use ecobee::hvac_mode_name;
+use error::ApiError;
+use query::{HealthQuery, RawQuery, RulesQuery, SchedulesQuery, StatusQuery, ThermostatsQuery};
+use request_id::Traced;
+use response::{HealthStatus, ThermostatStatus, ThermostatSummary};
+use rules::{self, FiringOutcome, RuleEngine, Trigger};
+use schedules::Scheduler;
+use state::unix_time;
+use temperature::{Temperature, Units};
+use thermostat::{Climate, Event, Forecast, Program, Runtime, Settings, Thermostat, Weather};
+use Result;
+
+/// Seconds between updates of the simulated room temperature.
+const TICK: u64 = 10;
+/// Tenths of a degree Fahrenheit the room warms or cools per tick while the
+/// equipment runs.
+const EQUIPMENT_RATE: f32 = 2.5;
+/// Share of the gap to the outdoor temperature the room loses per tick.
+const LEAKAGE: f32 = 0.005;
+/// Tenths of a degree the room may stray past the setpoint before the
+/// equipment starts.
+const DEADBAND: f32 = 5.0;
+/// In auto mode, tenths of a degree either side of the target where heating
+/// and cooling start, the same spread ecobee holds use.
+const AUTO_SPREAD: i32 = 36;
+
+const OUTDOOR_TEMPERATURE: i32 = 500;
+/// The comfort settings of the simulated program by climate ref, with
+/// their names and setpoints. The program always follows the first one.
+const CLIMATES: &[(&str, &str, i32)] = &[
+    ("home", "Home", 700),
+    ("away", "Away", 620),
+    ("sleep", "Sleep", 660),
+];
+const PROGRAM_TEMPERATURE: i32 = 700;
+const HUMIDITY: i32 = 45;
+const DESIRED_HUMIDITY: i32 = 36;
+/// ecobee's default heat and cool ranges.
+const HEAT_RANGE: (i32, i32) = (450, 790);
+const COOL_RANGE: (i32, i32) = (650, 920);
+
+/// What the simulated equipment is doing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Equipment {
+    Idle,
+    Heating,
+    Cooling,
+}
+
+/// A single thermostat kept in memory, for developing automations and demos
+/// without an ecobee account. The room temperature drifts toward the setpoint
+/// while heating or cooling runs and toward the outdoor temperature
+/// otherwise.
+pub struct SimulatedThermostat {
+    account: String,
+    hvac_mode: &'static str,
+    target: Temperature,
+    /// In tenths of a degree Fahrenheit, kept fractional so slow drifts add up.
+    current: f32,
+    equipment: Equipment,
+    /// Whether `target` is a hold rather than the program's setpoint.
+    hold: bool,
+    /// The climate ref of a comfort setting hold.
+    hold_climate: Option<String>,
+    fan_min_on_time: u8,
+    use_celsius: bool,
+    modified: SystemTime,
+    status_modified: SystemTime,
+    /// When the room was last simulated, standing in for ecobee's polls.
+    last_tick: Option<SystemTime>,
+    rules: RuleEngine,
+    schedules: Scheduler,
+}
+
+impl SimulatedThermostat {
+    pub fn new(config: &Config, account: &AccountConfig) -> Self {
+        let now = SystemTime::now();
+
+        SimulatedThermostat {
+            account: account.name.clone(),
+            hvac_mode: "heat",
+            target: Temperature::from_tenths(PROGRAM_TEMPERATURE),
+            current: (PROGRAM_TEMPERATURE - 20) as f32,
+            equipment: Equipment::Idle,
+            hold: false,
+            hold_climate: None,
+            fan_min_on_time: 0,
+            use_celsius: config.units == Units::Celsius,
+            modified: now,
+            status_modified: now,
+            last_tick: None,
+            rules: RuleEngine::new(config),
+            schedules: Scheduler::new(config, &account.name),
+        }
+    }
+
+    fn identifier(&self) -> String {
+        format!("simulated-{}", self.account)
+    }
+
+    /// The simulated state in ecobee's shape, so it is reported the same way
+    /// as a real thermostat's.
+    fn thermostat(&self) -> Thermostat {
+        let events = if self.hold {
+            vec![Event {
+                kind: "hold".to_owned(),
+                name: "simulated hold".to_owned(),
+                running: true,
+                heat_hold_temp: self.target,
+                cool_hold_temp: self.target,
+                hold_climate_ref: self.hold_climate.clone().unwrap_or_default(),
+                ..Event::default()
+            }]
+        } else {
+            Vec::new()
+        };
+
+        Thermostat {
+            identifier: self.identifier(),
+            name: "Simulator".to_owned(),
+            last_modified: timestamp(self.modified),
+            runtime: Runtime {
+                connected: true,
+                last_status_modified: timestamp(self.status_modified),
+                actual_temperature: Temperature::from_tenths(self.current.round() as i32),
+                actual_humidity: HUMIDITY,
+                desired_heat: self.target,
+                desired_cool: self.target,
+                desired_humidity: DESIRED_HUMIDITY,
+                ..Runtime::default()
+            },
+            settings: Settings {
+                hvac_mode: self.hvac_mode.to_owned(),
+                use_celsius: self.use_celsius,
+                heat_range_low: Temperature::from_tenths(HEAT_RANGE.0),
+                heat_range_high: Temperature::from_tenths(HEAT_RANGE.1),
+                cool_range_low: Temperature::from_tenths(COOL_RANGE.0),
+                cool_range_high: Temperature::from_tenths(COOL_RANGE.1),
+                fan_min_on_time: i32::from(self.fan_min_on_time),
+                ..Settings::default()
+            },
+            program: Some(Program {
+                climates: CLIMATES
+                    .iter()
+                    .map(|&(climate_ref, name, temperature)| Climate {
+                        name: name.to_owned(),
+                        climate_ref: climate_ref.to_owned(),
+                        heat_temp: Temperature::from_tenths(temperature),
+                        cool_temp: Temperature::from_tenths(temperature),
+                        ..Climate::default()
+                    })
+                    .collect(),
+                current_climate_ref: CLIMATES[0].0.to_owned(),
+                ..Program::default()
+            }),
+            weather: Some(Weather {
+                forecasts: vec![Forecast {
+                    temperature: Temperature::from_tenths(OUTDOOR_TEMPERATURE),
+                    ..Forecast::default()
+                }],
+                ..Weather::default()
+            }),
+            events,
+            ..Thermostat::default()
+        }
+    }
+
+    fn status(&self) -> ThermostatStatus {
+        self.thermostat().status(SystemTime::now())
+    }
+
+    /// Setpoints below `heat` start heating and above `cool` start cooling.
+    fn setpoints(&self) -> (Option<f32>, Option<f32>) {
+        let target = self.target.tenths();
+
+        match self.hvac_mode {
+            "heat" => (Some(target as f32), None),
+            "cool" => (None, Some(target as f32)),
+            "auto" => (
+                Some((target - AUTO_SPREAD) as f32),
+                Some((target + AUTO_SPREAD) as f32),
+            ),
+            _ => (None, None),
+        }
+    }
+
+    /// Advances the simulation by one tick.
+    fn tick(&mut self) {
+        self.last_tick = Some(SystemTime::now());
+        let (heat, cool) = self.setpoints();
+
+        // Run until the setpoint is reached, then wait for the room to drift
+        // past the deadband before starting again.
+        let equipment = match (heat, cool) {
+            (Some(heat), _) if self.current < heat - DEADBAND => Equipment::Heating,
+            (Some(heat), _) if self.equipment == Equipment::Heating && self.current < heat => {
+                Equipment::Heating
+            }
+            (_, Some(cool)) if self.current > cool + DEADBAND => Equipment::Cooling,
+            (_, Some(cool)) if self.equipment == Equipment::Cooling && self.current > cool => {
+                Equipment::Cooling
+            }
+            _ => Equipment::Idle,
+        };
+
+        if equipment != self.equipment {
+            debug!(
+                "simulated thermostat of {} is now {:?} at {:.1}°F",
+                self.account,
+                equipment,
+                self.current / 10.0
+            );
+            self.equipment = equipment;
+            self.status_modified = SystemTime::now();
+        }
+
+        self.current += (OUTDOOR_TEMPERATURE as f32 - self.current) * LEAKAGE;
+        match self.equipment {
+            Equipment::Heating => self.current += EQUIPMENT_RATE,
+            Equipment::Cooling => self.current -= EQUIPMENT_RATE,
+            Equipment::Idle => (),
+        }
+    }
+
+    /// Reports ticks like ecobee polls, so `/readyz` fails once the
+    /// simulation stops advancing. There is nothing to sign in to, the
+    /// simulator counts as authenticated once it has a state.
+    fn health(&self) -> HealthStatus {
+        HealthStatus {
+            authenticated: self.last_tick.is_some(),
+            token_age: None,
+            last_poll: self.last_tick.map(unix_time),
+            last_poll_age: self.last_tick.map(|tick| {
+                SystemTime::now()
+                    .duration_since(tick)
+                    .map(|age| age.as_secs())
+                    .unwrap_or(0)
+            }),
+            last_error: None,
+            thermostats: 1,
+            circuit: "closed",
+            pending_changes: 0,
+            conflicts: Vec::new(),
+        }
+    }
+}
+
+/// Stands in for ecobee's modification timestamps, which HomeKit clients
+/// only compare for changes.
+fn timestamp(time: SystemTime) -> String {
+    unix_time(time).to_string()
+}
+
+impl Actor for SimulatedThermostat {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        info!("simulating a thermostat for account {}", self.account);
+        // The initial state is ready right away, like a first poll.
+        self.last_tick = Some(SystemTime::now());
+
+        ctx.run_interval(Duration::from_secs(TICK), |simulator, ctx| {
+            simulator.tick();
+
+            let thermostats = [simulator.thermostat()];
+            let mut firings = simulator.schedules.tick(&thermostats);
+            firings.extend(simulator.rules.evaluate(&thermostats));
+            for firing in firings {
+                rules::dispatch(ctx.address(), firing);
+            }
+        });
+    }
+}
+
+impl SimulatedThermostat {
+    /// The simulated thermostat, if `selector` names it or is left out.
+    fn select(&self, selector: Option<&str>) -> Result<Thermostat> {
+        let thermostat = self.thermostat();
+        match selector {
+            Some(selector) if !thermostat.matches(selector) => {
+                Err(ApiError::UnknownThermostat(selector.to_owned()).into())
+            }
+            _ => Ok(thermostat),
+        }
+    }
+}
+
+impl Handler<StatusQuery> for SimulatedThermostat {
+    type Result = Result<ThermostatStatus>;
+
+    fn handle(&mut self, query: StatusQuery, _: &mut Self::Context) -> Self::Result {
+        self.select(query.0.as_deref())
+            .map(|thermostat| thermostat.status(SystemTime::now()))
+    }
+}
+
+impl Handler<RawQuery> for SimulatedThermostat {
+    type Result = Result<Thermostat>;
+
+    fn handle(&mut self, query: RawQuery, _: &mut Self::Context) -> Self::Result {
+        self.select(Some(&query.0))
+    }
+}
+
+impl Handler<ThermostatsQuery> for SimulatedThermostat {
+    type Result = Result<Vec<ThermostatSummary>>;
+
+    fn handle(&mut self, _: ThermostatsQuery, _: &mut Self::Context) -> Self::Result {
+        Ok(vec![self.thermostat().summary()])
+    }
+}
+
+impl Handler<HealthQuery> for SimulatedThermostat {
+    type Result = MessageResult<HealthQuery>;
+
+    fn handle(&mut self, _: HealthQuery, _: &mut Self::Context) -> Self::Result {
+        MessageResult(self.health())
+    }
+}
+
+impl Handler<RulesQuery> for SimulatedThermostat {
+    type Result = MessageResult<RulesQuery>;
+
+    fn handle(&mut self, _: RulesQuery, _: &mut Self::Context) -> Self::Result {
+        MessageResult(self.rules.report())
+    }
+}
+
+impl Handler<SchedulesQuery> for SimulatedThermostat {
+    type Result = MessageResult<SchedulesQuery>;
+
+    fn handle(&mut self, _: SchedulesQuery, _: &mut Self::Context) -> Self::Result {
+        MessageResult(self.schedules.report(&[self.thermostat()]))
+    }
+}
+
+impl Handler<Traced<ChangeThermostat>> for SimulatedThermostat {
+    type Result = Result<Box<Future<Item = ThermostatStatus, Error = Error> + Send + 'static>>;
+
+    fn handle(&mut self, traced: Traced<ChangeThermostat>, _: &mut Self::Context) -> Self::Result {
+        match traced.message {
+            ChangeThermostat::HvacMode(mode) => {
+                self.hvac_mode = hvac_mode_name(mode).ok_or_else(|| {
+                    ApiError::Validation(format!(
+                        "unknown heating/cooling state {}, expected 0 (off), 1 (heat), 2 (cool) or 3 (auto)",
+                        mode
+                    ))
+                })?;
+            }
+            // The simulated program never changes, so holds until the next
+            // transition last as long as indefinite ones.
+            ChangeThermostat::Temperature(temperature, _) => {
+                self.thermostat().settings.hold_setpoints(temperature)?;
+
+                self.target = temperature;
+                self.hold = true;
+                self.hold_climate = None;
+            }
+            ChangeThermostat::Climate(climate_ref, _) => {
+                self.target = self.thermostat().climate(&climate_ref)?.heat_temp;
+                self.hold = true;
+                self.hold_climate = Some(climate_ref);
+            }
+            ChangeThermostat::FanMinOnTime(minutes) => {
+                if minutes > MAX_FAN_MIN_ON_TIME {
+                    return Err(ApiError::Validation(format!(
+                        "the fan can run at most {} minutes per hour, not {}",
+                        MAX_FAN_MIN_ON_TIME, minutes
+                    ))
+                    .into());
+                }
+
+                self.fan_min_on_time = minutes;
+            }
+            ChangeThermostat::ResumeProgram => {
+                self.target = Temperature::from_tenths(PROGRAM_TEMPERATURE);
+                self.hold = false;
+                self.hold_climate = None;
+            }
+        }
+
+        info!(
+            "[{}] simulated thermostat of {} set to {} at {}{}",
+            traced.request_id,
+            self.account,
+            self.hvac_mode,
+            self.target,
+            if self.hold { " (hold)" } else { "" }
+        );
+        self.modified = SystemTime::now();
+
+        Ok(Box::new(future::ok(self.status())))
+    }
+}
+
+impl Handler<Traced<TargetedChange>> for SimulatedThermostat {
+    type Result = <SimulatedThermostat as Handler<Traced<ChangeThermostat>>>::Result;
+
+    fn handle(&mut self, traced: Traced<TargetedChange>, ctx: &mut Self::Context) -> Self::Result {
+        let Traced {
+            request_id,
+            message,
+        } = traced;
+        self.select(message.thermostat.as_deref())?;
+
+        self.handle(Traced::new(request_id, message.change), ctx)
+    }
+}
+
+impl Handler<Traced<RefreshNow>> for SimulatedThermostat {
+    type Result = Result<Box<Future<Item = (), Error = Error> + Send + 'static>>;
+
+    fn handle(&mut self, _: Traced<RefreshNow>, _: &mut Self::Context) -> Self::Result {
+        // The simulated state is always current.
+        Ok(Box::new(future::ok(())))
+    }
+}
+
+impl Handler<Reconfigure> for SimulatedThermostat {
+    type Result = ();
+
+    fn handle(&mut self, msg: Reconfigure, _: &mut Self::Context) {
+        self.use_celsius = msg.0.units == Units::Celsius;
+        self.rules.reconfigure(&msg.0);
+        self.schedules.reconfigure(&msg.0);
+    }
+}
+
+impl Handler<FiringOutcome> for SimulatedThermostat {
+    type Result = ();
+
+    fn handle(&mut self, outcome: FiringOutcome, _: &mut Self::Context) -> Self::Result {
+        match outcome.trigger {
+            Trigger::Rule => self.rules.record_outcome(outcome),
+            Trigger::Schedule => self.schedules.record_outcome(outcome),
+        }
+    }
+}
+
+impl Handler<Drain> for SimulatedThermostat {
+    type Result = Result<Box<Future<Item = (), Error = Error> + Send + 'static>>;
+
+    fn handle(&mut self, _: Drain, _: &mut Self::Context) -> Self::Result {
+        Ok(Box::new(future::ok(())))
+    }
+}
+
+impl Backend for SimulatedThermostat {
+    fn backend_addr(addr: Addr<Self>) -> BackendAddr {
+        BackendAddr::Simulator(addr)
+    }
+}
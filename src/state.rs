@@ -0,0 +1,64 @@
+//! What castform keeps in `state_dir` across restarts: the ecobee token,
+//! when schedules last ran and who is home, one JSON file each.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json;
+
+use Result;
+
+/// Seconds since the Unix epoch, as stored and reported.
+pub fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// The state stored at `path`, or the default if there is none yet. An
+/// invalid file, e.g. from an older castform, is logged and ignored; `what`
+/// names its contents for that.
+pub fn load_json_state<T: DeserializeOwned + Default>(path: &Path, what: &str) -> T {
+    let mut contents = String::new();
+    if File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut contents))
+        .is_err()
+    {
+        return T::default();
+    }
+
+    serde_json::from_str(&contents).unwrap_or_else(|e| {
+        warn!(
+            "ignoring {}, it is not a valid {}: {}",
+            path.display(),
+            what,
+            e
+        );
+        T::default()
+    })
+}
+
+/// Replaces the state stored at `path`, creating its directory if needed.
+/// Files are readable by castform's user only, since the token is among
+/// them, and synced so a crash right after doesn't lose them.
+pub fn save_json_state<T: Serialize>(path: &Path, state: &T) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(&serde_json::to_vec_pretty(state)?)?;
+    file.sync_all()?;
+
+    Ok(())
+}
@@ -0,0 +1,56 @@
+use std::env;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::process;
+use std::time::Duration;
+
+/// Minimal sd_notify(3) client, talking to the socket systemd passes to
+/// services started with `Type=notify`.
+pub struct Notifier {
+    socket: UnixDatagram,
+    path: PathBuf,
+}
+
+impl Notifier {
+    /// Returns `None` unless `NOTIFY_SOCKET` is set.
+    pub fn from_env() -> Option<Notifier> {
+        let path = PathBuf::from(env::var_os("NOTIFY_SOCKET")?);
+
+        if path.to_string_lossy().starts_with('@') {
+            warn!(
+                "abstract NOTIFY_SOCKET {} is not supported, not notifying systemd",
+                path.display()
+            );
+            return None;
+        }
+
+        match UnixDatagram::unbound() {
+            Ok(socket) => Some(Notifier { socket, path }),
+            Err(e) => {
+                warn!("failed to create the systemd notification socket: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Sends a state such as `READY=1`, logging failures.
+    pub fn notify(&self, state: &str) {
+        if let Err(e) = self.socket.send_to(state.as_bytes(), &self.path) {
+            warn!("failed to send {} to systemd: {}", state, e);
+        }
+    }
+}
+
+/// How often to send `WATCHDOG=1`, half the timeout systemd set for this
+/// process with `WatchdogSec=`.
+pub fn watchdog_interval() -> Option<Duration> {
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(process::id()) {
+            return None;
+        }
+    }
+
+    Some(Duration::from_micros(usec / 2))
+}
@@ -0,0 +1,118 @@
+use std::fmt;
+
+use error::ApiError;
+use Result;
+
+/// Temperature scale used for API input and output.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Units {
+    #[default]
+    #[serde(rename = "c")]
+    Celsius,
+    #[serde(rename = "f")]
+    Fahrenheit,
+}
+
+/// A temperature in ecobee's native representation, tenths of a degree
+/// Fahrenheit. Conversions to either scale happen only at the edges, so values
+/// read from and written to ecobee never lose precision.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Temperature(i32);
+
+impl Temperature {
+    pub fn from_tenths(tenths: i32) -> Self {
+        Temperature(tenths)
+    }
+
+    /// A temperature asked for by an API caller, which has to be a finite
+    /// number.
+    pub fn from_input(value: f32, units: Units) -> Result<Self> {
+        if !value.is_finite() {
+            return Err(ApiError::Validation("temperature must be a finite number".into()).into());
+        }
+
+        Ok(Temperature::from_units(value, units))
+    }
+
+    /// Rounds to the nearest tenth of a degree Fahrenheit. `value` has to be
+    /// finite, see `from_input`.
+    pub fn from_units(value: f32, units: Units) -> Self {
+        let fahrenheit = match units {
+            Units::Celsius => value * 1.8 + 32.0,
+            Units::Fahrenheit => value,
+        };
+
+        Temperature((fahrenheit * 10.0).round() as i32)
+    }
+
+    pub fn tenths(self) -> i32 {
+        self.0
+    }
+
+    pub fn offset(self, tenths: i32) -> Self {
+        Temperature(self.0 + tenths)
+    }
+
+    pub fn in_units(self, units: Units) -> f32 {
+        let fahrenheit = self.0 as f32 / 10.0;
+
+        match units {
+            Units::Celsius => (fahrenheit - 32.0) / 1.8,
+            Units::Fahrenheit => fahrenheit,
+        }
+    }
+
+    /// Rounds to the step the thermostat itself displays, half degrees when it
+    /// is set to Celsius and whole degrees otherwise, then converts to `units`
+    /// with one decimal of precision.
+    pub fn display(self, units: Units, use_celsius: bool) -> f32 {
+        let rounded = if use_celsius {
+            let celsius = (self.in_units(Units::Celsius) * 2.0).round() / 2.0;
+            Temperature::from_units(celsius, Units::Celsius)
+        } else {
+            let fahrenheit = self.in_units(Units::Fahrenheit).round();
+            Temperature::from_units(fahrenheit, Units::Fahrenheit)
+        };
+
+        (rounded.in_units(units) * 10.0).round() / 10.0
+    }
+}
+
+impl fmt::Display for Temperature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:.1}°C ({:.1}°F)",
+            self.in_units(Units::Celsius),
+            self.in_units(Units::Fahrenheit)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_input_rejects_non_finite_values() {
+        for &value in &[f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+            let error = Temperature::from_input(value, Units::Celsius).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                "invalid request: temperature must be a finite number"
+            );
+        }
+    }
+
+    #[test]
+    fn from_input_converts_to_tenths_fahrenheit() {
+        assert_eq!(
+            Temperature::from_input(21.5, Units::Celsius).unwrap(),
+            Temperature::from_tenths(707)
+        );
+        assert_eq!(
+            Temperature::from_input(68.0, Units::Fahrenheit).unwrap(),
+            Temperature::from_tenths(680)
+        );
+    }
+}
@@ -0,0 +1,697 @@
+//! Typed ecobee thermostat objects, as returned by `GET /1/thermostat`.
+//!
+//! Field names follow ecobee's camelCase JSON, so serializing gives back the
+//! document ecobee sent minus anything castform does not model. Temperatures
+//! are tenths of a degree Fahrenheit and, like most numbers here, can be
+//! negative: outdoor readings in winter, and ecobee's `-5002` placeholder for
+//! a missing value.
+//!
+//! Fields castform acts on, like the mode, scale, ranges and set points, are
+//! required so a malformed response fails the poll instead of reading as
+//! zero. Everything else defaults when a selection leaves it out.
+
+use std::time::SystemTime;
+
+use error::ApiError;
+use response::{ThermostatStatus, ThermostatSummary};
+use temperature::Temperature;
+use Result;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Thermostat {
+    pub identifier: String,
+    pub name: String,
+    pub last_modified: String,
+    pub runtime: Runtime,
+    pub settings: Settings,
+    #[serde(default)]
+    pub thermostat_rev: String,
+    #[serde(default)]
+    pub is_registered: bool,
+    #[serde(default)]
+    pub model_number: String,
+    #[serde(default)]
+    pub brand: String,
+    #[serde(default)]
+    pub features: String,
+    #[serde(default)]
+    pub thermostat_time: String,
+    #[serde(default)]
+    pub utc_time: String,
+    #[serde(default)]
+    pub extended_runtime: Option<ExtendedRuntime>,
+    #[serde(default)]
+    pub location: Option<Location>,
+    #[serde(default)]
+    pub program: Option<Program>,
+    #[serde(default)]
+    pub events: Vec<Event>,
+    #[serde(default)]
+    pub alerts: Vec<Alert>,
+    #[serde(default)]
+    pub remote_sensors: Vec<Sensor>,
+    #[serde(default)]
+    pub weather: Option<Weather>,
+    #[serde(default)]
+    pub devices: Vec<Device>,
+    #[serde(default)]
+    pub version: Option<Version>,
+}
+
+impl Thermostat {
+    /// The thermostat `selector` matches, or the first one without a
+    /// selector.
+    pub fn select<'a>(
+        thermostats: &'a [Thermostat],
+        selector: Option<&str>,
+    ) -> Option<&'a Thermostat> {
+        match selector {
+            Some(selector) => thermostats
+                .iter()
+                .find(|thermostat| thermostat.matches(selector)),
+            None => thermostats.first(),
+        }
+    }
+
+    /// Whether `selector` is this thermostat's identifier or, ignoring case,
+    /// its name.
+    pub fn matches(&self, selector: &str) -> bool {
+        self.identifier == selector || self.name.eq_ignore_ascii_case(selector)
+    }
+
+    /// The comfort setting with `climate_ref` from the thermostat's program.
+    pub fn climate(&self, climate_ref: &str) -> Result<&Climate> {
+        let climates = self
+            .program
+            .as_ref()
+            .map_or(&[][..], |program| &program.climates[..]);
+
+        climates
+            .iter()
+            .find(|climate| climate.climate_ref == climate_ref)
+            .ok_or_else(|| {
+                ApiError::Validation(format!(
+                    "unknown comfort setting `{}`, expected one of {}",
+                    climate_ref,
+                    climates
+                        .iter()
+                        .map(|climate| climate.climate_ref.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ))
+                .into()
+            })
+    }
+
+    pub fn summary(&self) -> ThermostatSummary {
+        ThermostatSummary {
+            identifier: self.identifier.clone(),
+            name: self.name.clone(),
+            hvac_mode: self.settings.hvac_mode.clone(),
+        }
+    }
+
+    pub fn status(&self, updated: SystemTime) -> ThermostatStatus {
+        ThermostatStatus::from(self, updated)
+    }
+}
+
+/// The current state of the thermostat, updated every few minutes.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Runtime {
+    #[serde(default)]
+    pub runtime_rev: String,
+    #[serde(default)]
+    pub connected: bool,
+    #[serde(default)]
+    pub first_connected: String,
+    #[serde(default)]
+    pub connect_date_time: String,
+    #[serde(default)]
+    pub disconnect_date_time: String,
+    #[serde(default)]
+    pub last_modified: String,
+    #[serde(default)]
+    pub last_status_modified: String,
+    #[serde(default)]
+    pub runtime_date: String,
+    #[serde(default)]
+    pub runtime_interval: i32,
+    pub actual_temperature: Temperature,
+    /// Percent relative humidity.
+    #[serde(default)]
+    pub actual_humidity: i32,
+    #[serde(default)]
+    pub raw_temperature: Temperature,
+    #[serde(default)]
+    pub show_icon_mode: i32,
+    pub desired_heat: Temperature,
+    pub desired_cool: Temperature,
+    #[serde(default)]
+    pub desired_humidity: i32,
+    #[serde(default)]
+    pub desired_dehumidity: i32,
+    #[serde(default)]
+    pub desired_fan_mode: String,
+    #[serde(default)]
+    pub desired_heat_range: Vec<Temperature>,
+    #[serde(default)]
+    pub desired_cool_range: Vec<Temperature>,
+    #[serde(default, rename = "actualAQAccuracy")]
+    pub actual_aq_accuracy: i32,
+    #[serde(default, rename = "actualAQScore")]
+    pub actual_aq_score: i32,
+    #[serde(default, rename = "actualCO2")]
+    pub actual_co2: i32,
+    #[serde(default, rename = "actualVOC")]
+    pub actual_voc: i32,
+}
+
+/// The last three five minute intervals of runtime data, oldest first.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ExtendedRuntime {
+    pub last_reading_timestamp: String,
+    pub runtime_date: String,
+    pub runtime_interval: i32,
+    pub actual_temperature: Vec<Temperature>,
+    pub actual_humidity: Vec<i32>,
+    pub desired_heat: Vec<Temperature>,
+    pub desired_cool: Vec<Temperature>,
+    pub desired_humidity: Vec<i32>,
+    pub desired_dehumidity: Vec<i32>,
+    pub dm_offset: Vec<i32>,
+    pub hvac_mode: Vec<String>,
+    /// Seconds each piece of equipment ran in each interval.
+    pub heat_pump1: Vec<i32>,
+    pub heat_pump2: Vec<i32>,
+    pub aux_heat1: Vec<i32>,
+    pub aux_heat2: Vec<i32>,
+    pub aux_heat3: Vec<i32>,
+    pub cool1: Vec<i32>,
+    pub cool2: Vec<i32>,
+    pub fan: Vec<i32>,
+    pub humidifier: Vec<i32>,
+    pub dehumidifier: Vec<i32>,
+    pub economizer: Vec<i32>,
+    pub ventilator: Vec<i32>,
+    pub current_electricity_bill: i32,
+    pub projected_electricity_bill: i32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Settings {
+    /// One of `auto`, `auxHeatOnly`, `cool`, `heat` or `off`.
+    pub hvac_mode: String,
+    #[serde(default)]
+    pub last_service_date: String,
+    #[serde(default)]
+    pub service_remind_me: bool,
+    #[serde(default)]
+    pub months_between_service: i32,
+    #[serde(default)]
+    pub remind_me_date: String,
+    #[serde(default)]
+    pub vent: String,
+    #[serde(default)]
+    pub ventilator_min_on_time: i32,
+    #[serde(default)]
+    pub service_remind_technician: bool,
+    #[serde(default)]
+    pub ei_location: String,
+    #[serde(default)]
+    pub cold_temp_alert: Temperature,
+    #[serde(default)]
+    pub cold_temp_alert_enabled: bool,
+    #[serde(default)]
+    pub hot_temp_alert: Temperature,
+    #[serde(default)]
+    pub hot_temp_alert_enabled: bool,
+    #[serde(default)]
+    pub cool_stages: i32,
+    #[serde(default)]
+    pub heat_stages: i32,
+    #[serde(default)]
+    pub max_set_back: i32,
+    #[serde(default)]
+    pub max_set_forward: i32,
+    #[serde(default)]
+    pub quick_save_set_back: i32,
+    #[serde(default)]
+    pub quick_save_set_forward: i32,
+    #[serde(default)]
+    pub has_heat_pump: bool,
+    #[serde(default)]
+    pub has_forced_air: bool,
+    #[serde(default)]
+    pub has_boiler: bool,
+    #[serde(default)]
+    pub has_humidifier: bool,
+    #[serde(default)]
+    pub has_erv: bool,
+    #[serde(default)]
+    pub has_hrv: bool,
+    #[serde(default)]
+    pub condensation_avoid: bool,
+    pub use_celsius: bool,
+    #[serde(default)]
+    pub use_time_format12: bool,
+    #[serde(default)]
+    pub locale: String,
+    #[serde(default)]
+    pub humidity: String,
+    #[serde(default)]
+    pub humidifier_mode: String,
+    #[serde(default)]
+    pub backlight_on_intensity: i32,
+    #[serde(default)]
+    pub backlight_sleep_intensity: i32,
+    #[serde(default)]
+    pub backlight_off_time: i32,
+    #[serde(default)]
+    pub sound_tick_volume: i32,
+    #[serde(default)]
+    pub sound_alert_volume: i32,
+    #[serde(default)]
+    pub compressor_protection_min_time: i32,
+    #[serde(default)]
+    pub compressor_protection_min_temp: Temperature,
+    #[serde(default)]
+    pub stage1_heating_differential_temp: i32,
+    #[serde(default)]
+    pub stage1_cooling_differential_temp: i32,
+    #[serde(default)]
+    pub stage1_heating_dissipation_time: i32,
+    #[serde(default)]
+    pub stage1_cooling_dissipation_time: i32,
+    #[serde(default)]
+    pub heat_pump_reversal_on_cool: bool,
+    #[serde(default)]
+    pub fan_control_required: bool,
+    #[serde(default)]
+    pub fan_min_on_time: i32,
+    #[serde(default)]
+    pub heat_cool_min_delta: i32,
+    #[serde(default)]
+    pub temp_correction: i32,
+    #[serde(default)]
+    pub hold_action: String,
+    #[serde(default)]
+    pub heat_pump_ground_water: bool,
+    #[serde(default)]
+    pub has_electric: bool,
+    #[serde(default)]
+    pub has_dehumidifier: bool,
+    #[serde(default)]
+    pub dehumidifier_mode: String,
+    #[serde(default)]
+    pub dehumidifier_level: i32,
+    #[serde(default, rename = "dehumidifyWithAC")]
+    pub dehumidify_with_ac: bool,
+    #[serde(default)]
+    pub dehumidify_overcool_offset: i32,
+    #[serde(default)]
+    pub auto_heat_cool_feature_enabled: bool,
+    #[serde(default)]
+    pub wifi_offline_alert: bool,
+    #[serde(default)]
+    pub heat_min_temp: Temperature,
+    #[serde(default)]
+    pub heat_max_temp: Temperature,
+    #[serde(default)]
+    pub cool_min_temp: Temperature,
+    #[serde(default)]
+    pub cool_max_temp: Temperature,
+    pub heat_range_high: Temperature,
+    pub heat_range_low: Temperature,
+    pub cool_range_high: Temperature,
+    pub cool_range_low: Temperature,
+    #[serde(default)]
+    pub user_access_code: String,
+    #[serde(default)]
+    pub user_access_setting: i32,
+    #[serde(default)]
+    pub aux_runtime_alert: i32,
+    #[serde(default)]
+    pub aux_outdoor_temp_alert: Temperature,
+    #[serde(default)]
+    pub aux_max_outdoor_temp: Temperature,
+    #[serde(default)]
+    pub aux_runtime_alert_notify: bool,
+    #[serde(default)]
+    pub aux_outdoor_temp_alert_notify: bool,
+    #[serde(default)]
+    pub aux_runtime_alert_notify_technician: bool,
+    #[serde(default)]
+    pub aux_outdoor_temp_alert_notify_technician: bool,
+    #[serde(default)]
+    pub disable_pre_heating: bool,
+    #[serde(default)]
+    pub disable_pre_cooling: bool,
+    #[serde(default)]
+    pub installer_code_required: bool,
+    #[serde(default)]
+    pub dr_accept: String,
+    #[serde(default)]
+    pub is_rental_property: bool,
+    #[serde(default)]
+    pub use_zone_controller: bool,
+    #[serde(default)]
+    pub random_start_delay_cool: i32,
+    #[serde(default)]
+    pub random_start_delay_heat: i32,
+    #[serde(default)]
+    pub humidity_high_alert: i32,
+    #[serde(default)]
+    pub humidity_low_alert: i32,
+    #[serde(default)]
+    pub disable_heat_pump_alerts: bool,
+    #[serde(default)]
+    pub disable_alerts_on_idt: bool,
+    #[serde(default)]
+    pub humidity_alert_notify: bool,
+    #[serde(default)]
+    pub humidity_alert_notify_technician: bool,
+    #[serde(default)]
+    pub temp_alert_notify: bool,
+    #[serde(default)]
+    pub temp_alert_notify_technician: bool,
+    #[serde(default)]
+    pub monthly_electricity_bill_limit: i32,
+    #[serde(default)]
+    pub enable_electricity_bill_alert: bool,
+    #[serde(default)]
+    pub enable_projected_electricity_bill_alert: bool,
+    #[serde(default)]
+    pub electricity_billing_day_of_month: i32,
+    #[serde(default)]
+    pub electricity_bill_cycle_months: i32,
+    #[serde(default)]
+    pub electricity_bill_start_month: i32,
+    #[serde(default)]
+    pub ventilator_min_on_time_home: i32,
+    #[serde(default)]
+    pub ventilator_min_on_time_away: i32,
+    #[serde(default)]
+    pub backlight_off_during_sleep: bool,
+    #[serde(default)]
+    pub auto_away: bool,
+    #[serde(default)]
+    pub smart_circulation: bool,
+    #[serde(default)]
+    pub follow_me_comfort: bool,
+    #[serde(default)]
+    pub ventilator_type: String,
+    #[serde(default)]
+    pub is_ventilator_timer_on: bool,
+    #[serde(default)]
+    pub ventilator_off_date_time: String,
+    #[serde(default, rename = "hasUVFilter")]
+    pub has_uv_filter: bool,
+    #[serde(default)]
+    pub cooling_lockout: bool,
+    #[serde(default)]
+    pub ventilator_free_cooling: bool,
+    #[serde(default)]
+    pub dehumidify_when_heating: bool,
+    #[serde(default)]
+    pub ventilator_dehumidify: bool,
+    #[serde(default)]
+    pub group_ref: String,
+    #[serde(default)]
+    pub group_name: String,
+    #[serde(default)]
+    pub group_setting: i32,
+}
+
+impl Settings {
+    /// Splits a target temperature into heat and cool hold setpoints 3.6°F
+    /// either side of it. Targets no setpoint could honor are rejected, the
+    /// rest are clamped to the thermostat's heat and cool ranges.
+    pub fn hold_setpoints(&self, target: Temperature) -> Result<(Temperature, Temperature)> {
+        if target < self.heat_range_low || target > self.cool_range_high {
+            return Err(ApiError::Validation(format!(
+                "temperature {} is outside of the supported range {} to {}",
+                target, self.heat_range_low, self.cool_range_high,
+            ))
+            .into());
+        }
+
+        let heat = target
+            .offset(-36)
+            .max(self.heat_range_low)
+            .min(self.heat_range_high);
+        let cool = target
+            .offset(36)
+            .max(self.cool_range_low)
+            .min(self.cool_range_high);
+
+        Ok((heat, cool))
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Location {
+    pub time_zone_offset_minutes: i32,
+    /// IANA name such as `America/Toronto`.
+    pub time_zone: String,
+    pub is_daylight_saving: bool,
+    pub street_address: String,
+    pub city: String,
+    pub province_state: String,
+    pub country: String,
+    pub postal_code: String,
+    pub phone_number: String,
+    pub map_coordinates: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Program {
+    /// Seven days, starting Monday, of 48 half hour slots naming a climate.
+    pub schedule: Vec<Vec<String>>,
+    pub climates: Vec<Climate>,
+    pub current_climate_ref: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Climate {
+    pub name: String,
+    pub climate_ref: String,
+    pub is_occupied: bool,
+    pub is_optimized: bool,
+    pub cool_fan: String,
+    pub heat_fan: String,
+    pub vent: String,
+    pub ventilator_min_on_time: i32,
+    pub owner: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub colour: i32,
+    pub cool_temp: Temperature,
+    pub heat_temp: Temperature,
+    pub sensors: Vec<ClimateSensor>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct ClimateSensor {
+    pub id: String,
+    pub name: String,
+}
+
+/// A remote or built-in sensor reporting temperature, humidity or occupancy.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Sensor {
+    pub id: String,
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub code: String,
+    pub in_use: bool,
+    pub capability: Vec<Capability>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct Capability {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// As sent by ecobee, e.g. `"721"` for temperature or `"true"` for
+    /// occupancy.
+    pub value: String,
+}
+
+/// A hold, vacation, demand response or other event overriding the program.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Event {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub name: String,
+    pub running: bool,
+    pub start_date: String,
+    pub start_time: String,
+    pub end_date: String,
+    pub end_time: String,
+    pub is_occupied: bool,
+    pub is_cool_off: bool,
+    pub is_heat_off: bool,
+    pub cool_hold_temp: Temperature,
+    pub heat_hold_temp: Temperature,
+    pub fan: String,
+    pub vent: String,
+    pub ventilator_min_on_time: i32,
+    pub is_optional: bool,
+    pub is_temperature_relative: bool,
+    pub cool_relative_temp: i32,
+    pub heat_relative_temp: i32,
+    pub is_temperature_absolute: bool,
+    pub duty_cycle_percentage: i32,
+    pub fan_min_on_time: i32,
+    pub occupied_sensor_active: bool,
+    pub unoccupied_sensor_active: bool,
+    pub dr_ramp_up_temp: i32,
+    pub dr_ramp_up_time: i32,
+    pub link_ref: String,
+    pub hold_climate_ref: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Alert {
+    pub acknowledge_ref: String,
+    pub date: String,
+    pub time: String,
+    pub severity: String,
+    pub text: String,
+    pub alert_number: i32,
+    pub alert_type: String,
+    pub is_operator_alert: bool,
+    pub reminder: String,
+    pub show_idt: bool,
+    pub show_web: bool,
+    pub send_email: bool,
+    pub acknowledgement: String,
+    pub remind_me_later: bool,
+    pub thermostat_identifier: String,
+    pub notification_type: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Weather {
+    pub timestamp: String,
+    pub weather_station: String,
+    /// The current conditions first, then the forecast.
+    pub forecasts: Vec<Forecast>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Forecast {
+    pub weather_symbol: i32,
+    pub date_time: String,
+    pub condition: String,
+    pub temperature: Temperature,
+    /// Millibars.
+    pub pressure: i32,
+    pub relative_humidity: i32,
+    pub dewpoint: Temperature,
+    /// Meters.
+    pub visibility: i32,
+    /// Miles per hour.
+    pub wind_speed: i32,
+    pub wind_gust: i32,
+    pub wind_direction: String,
+    pub wind_bearing: i32,
+    /// Probability of precipitation in percent.
+    pub pop: i32,
+    pub temp_high: Temperature,
+    pub temp_low: Temperature,
+    pub sky: i32,
+}
+
+/// Equipment wired to the thermostat, with its sensors and outputs.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Device {
+    pub device_id: i32,
+    pub name: String,
+    pub sensors: Vec<DeviceSensor>,
+    pub outputs: Vec<Output>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct DeviceSensor {
+    pub sensor_id: String,
+    pub name: String,
+    pub manufacturer: String,
+    pub model: String,
+    pub zone: i32,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub usage: String,
+    pub number_of_bits: i32,
+    pub bconstant: i32,
+    pub thermistor_size: i32,
+    pub temp_correction: i32,
+    pub gain: i32,
+    pub max_voltage: i32,
+    pub multiplier: i32,
+    pub states: Vec<State>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct State {
+    pub max_value: i32,
+    pub min_value: i32,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub actions: Vec<Action>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Action {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub send_alert: bool,
+    pub send_update: bool,
+    pub activation_delay: i32,
+    pub deactivation_delay: i32,
+    pub min_action_duration: i32,
+    pub heat_adjust_temp: i32,
+    pub cool_adjust_temp: i32,
+    pub activate_relay: String,
+    pub activate_relay_open: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Output {
+    pub name: String,
+    pub zone: i32,
+    pub output_id: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub send_update: bool,
+    pub active_closed: bool,
+    pub activation_time: i32,
+    pub deactivation_time: i32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Version {
+    pub thermostat_firmware_version: String,
+}
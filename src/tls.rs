@@ -0,0 +1,24 @@
+use openssl::ssl::{SslAcceptor, SslAcceptorBuilder, SslFiletype, SslMethod, SslVerifyMode};
+
+use Result;
+
+/// Builds the acceptor used by the HTTP listener from PEM files. When
+/// `client_ca` is given, clients must present a certificate signed by it.
+pub fn build_acceptor(
+    cert: &str,
+    key: &str,
+    client_ca: Option<&str>,
+) -> Result<SslAcceptorBuilder> {
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+
+    builder.set_private_key_file(key, SslFiletype::PEM)?;
+    builder.set_certificate_chain_file(cert)?;
+    builder.check_private_key()?;
+
+    if let Some(ca) = client_ca {
+        builder.set_ca_file(ca)?;
+        builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    }
+
+    Ok(builder)
+}